@@ -0,0 +1,282 @@
+//! Handles the HTTP API for labelling streams with metadata at runtime.
+use std::{collections::HashMap, sync::Arc};
+
+use anyhow::{Context as AnyhowContext, Result};
+use percent_encoding::percent_decode_str;
+use subtle::ConstantTimeEq;
+use tokio::sync::RwLock;
+use tracing::{debug, error, info};
+use warp::{
+    filters::{path::Tail, BoxedFilter},
+    hyper::StatusCode,
+    reject::Reject,
+    reply::Response,
+    Filter, Rejection, Reply,
+};
+
+use crate::{config::MetaSource, context::Context, error_response, meta::MetaFile};
+
+/// The maximum size of a request body accepted by the API, in bytes.
+const MAX_BODY_SIZE: u64 = 64 * 1024;
+
+/// A rejection for requests without a valid API token.
+#[derive(Debug)]
+pub struct Unauthorized;
+
+impl Reject for Unauthorized {}
+
+/// Create the routes of the metadata API. The API is disabled if no token is
+/// given. If a metadata source is given, every change is written back to it.
+pub fn routes(
+    ctx: Arc<RwLock<Context>>,
+    token: Option<String>,
+    persist: Option<Arc<MetaSource>>,
+) -> BoxedFilter<(Response,)> {
+    let ctx = warp::any().map(move || ctx.clone());
+    let persist = warp::any().map(move || persist.clone());
+    // streams are either `<stream>` or `<application>/<stream>`, and may contain
+    // characters which are escaped in paths
+    let stream = warp::path!("api" / "metadata" / ..).and(warp::path::tail()).and_then(
+        |tail: Tail| async move {
+            match percent_decode_str(tail.as_str()).decode_utf8_lossy() {
+                stream if stream.is_empty() => Err(warp::reject::not_found()),
+                stream => Ok(stream.into_owned()),
+            }
+        },
+    );
+    // check the token before the method, so that the routes are not found if the
+    // API is disabled
+    let list = warp::path!("api" / "metadata")
+        .and(warp::path::end())
+        .and(authorize(token.clone()))
+        .and(warp::get())
+        .and(ctx.clone())
+        .then(list);
+    let get = stream.and(authorize(token.clone())).and(warp::get()).and(ctx.clone()).then(get);
+    let put = stream
+        .and(authorize(token.clone()))
+        .and(warp::put())
+        .and(warp::body::content_length_limit(MAX_BODY_SIZE))
+        .and(warp::body::json())
+        .and(ctx.clone())
+        .and(persist.clone())
+        .then(put);
+    let delete =
+        stream.and(authorize(token)).and(warp::delete()).and(ctx).and(persist).then(delete);
+    list.or(get).unify().or(put).unify().or(delete).unify().boxed()
+}
+
+/// Require the given bearer token, if there is one.
+pub fn require_token(token: Option<String>) -> BoxedFilter<()> {
+    match token {
+        Some(token) => authorize(Some(token)).boxed(),
+        None => warp::any().boxed(),
+    }
+}
+
+/// Require the given bearer token, rejecting every request if there is none.
+fn authorize(token: Option<String>) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    let expected = token.map(|token| Arc::new(format!("Bearer {}", token)));
+    warp::header::optional::<String>("authorization")
+        .and_then(move |header: Option<String>| {
+            let expected = expected.clone();
+            async move {
+                match (expected, header) {
+                    // the API is disabled without a token
+                    (None, _) => Err(warp::reject::not_found()),
+                    // compare in constant time, so the token cannot be guessed by timing
+                    (Some(expected), Some(header))
+                        if bool::from(expected.as_bytes().ct_eq(header.as_bytes())) =>
+                    {
+                        Ok(())
+                    }
+                    _ => Err(warp::reject::custom(Unauthorized)),
+                }
+            }
+        })
+        .untuple_one()
+}
+
+/// Return the names of the streams with metadata.
+async fn list(ctx: Arc<RwLock<Context>>) -> Response {
+    let mut streams = ctx.read().await.metadata.streams();
+    streams.sort();
+    warp::reply::json(&streams).into_response()
+}
+
+/// Return the metadata of a stream.
+async fn get(stream: String, ctx: Arc<RwLock<Context>>) -> Response {
+    match ctx.read().await.metadata.for_stream(&stream) {
+        Some(values) => warp::reply::json(values).into_response(),
+        None => error_response(StatusCode::NOT_FOUND, "NOT_FOUND"),
+    }
+}
+
+/// Replace the metadata of a stream.
+async fn put(
+    stream: String,
+    values: HashMap<String, String>,
+    ctx: Arc<RwLock<Context>>,
+    persist: Option<Arc<MetaSource>>,
+) -> Response {
+    let mut ctx = ctx.write().await;
+    let previous = match ctx.metadata.set_stream(&stream, values) {
+        Ok(previous) => previous,
+        Err(err) => {
+            debug!("invalid metadata for stream {}: {}", stream, err);
+            return error_response(StatusCode::BAD_REQUEST, "UNKNOWN_FIELD");
+        }
+    };
+    if save(&mut ctx.metadata, &stream, previous, persist).await.is_err() {
+        return error_response(StatusCode::INTERNAL_SERVER_ERROR, "PERSIST_FAILED");
+    }
+    ctx.exporter_info.set_metadata(&ctx.metadata);
+    info!("updated metadata of stream {}", stream);
+    warp::reply::json(&ctx.metadata.for_stream(&stream)).into_response()
+}
+
+/// Remove the metadata of a stream.
+async fn delete(
+    stream: String,
+    ctx: Arc<RwLock<Context>>,
+    persist: Option<Arc<MetaSource>>,
+) -> Response {
+    let mut ctx = ctx.write().await;
+    let previous = match ctx.metadata.remove_stream(&stream) {
+        Some(previous) => previous,
+        None => return error_response(StatusCode::NOT_FOUND, "NOT_FOUND"),
+    };
+    if save(&mut ctx.metadata, &stream, Some(previous), persist).await.is_err() {
+        return error_response(StatusCode::INTERNAL_SERVER_ERROR, "PERSIST_FAILED");
+    }
+    ctx.exporter_info.set_metadata(&ctx.metadata);
+    info!("removed metadata of stream {}", stream);
+    StatusCode::NO_CONTENT.into_response()
+}
+
+/// Write a change to the metadata of a stream back to its source, if it should
+/// be persisted. If it cannot be written, the previous metadata of the stream
+/// is restored, so that the exporter does not diverge from its source. The file
+/// is written on a blocking thread, so that the runtime is not blocked.
+async fn save(
+    metadata: &mut MetaFile,
+    stream: &str,
+    previous: Option<HashMap<String, String>>,
+    persist: Option<Arc<MetaSource>>,
+) -> Result<()> {
+    let source = match persist {
+        Some(source) => source,
+        None => return Ok(()),
+    };
+    let snapshot = metadata.clone();
+    let res = tokio::task::spawn_blocking(move || source.save(&snapshot))
+        .await
+        .context("Failed to write meta file")
+        .and_then(|res| res);
+    if let Err(err) = &res {
+        error!("Failed to persist metadata: {:#}", err);
+        match previous {
+            Some(values) => metadata.metadata.insert(stream.to_owned(), values),
+            None => metadata.metadata.remove(stream),
+        };
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, sync::Arc};
+
+    use tokio::sync::RwLock;
+    use warp::hyper::StatusCode;
+
+    use super::{routes, Unauthorized};
+    use crate::{
+        config::MetaSource,
+        context::{test_context, Context},
+        meta::{Format, MetaField, MetaFile},
+        rules::ClientRules,
+    };
+
+    #[tokio::test]
+    async fn test_metadata_api() {
+        let metadata = MetaFile { fields: vec![MetaField::new("customer")], ..Default::default() };
+        let ctx = test_context(metadata, ClientRules::default());
+        let ctx = Arc::new(RwLock::new(ctx));
+        let api = routes(ctx.clone(), Some("secret".to_owned()), None);
+        let request = |method: &str, body: &str| {
+            warp::test::request()
+                .method(method)
+                .path("/api/metadata/my-stream")
+                .header("authorization", "Bearer secret")
+                .body(body)
+        };
+        // whether the value of a stream is exported in the metadata values metric
+        let exported = |ctx: &Context, stream: &str, value: &str| {
+            ctx.gather()
+                .iter()
+                .filter(|family| family.get_name() == "nginx_rtmp_exporter_metadata_values")
+                .flat_map(|family| family.get_metric())
+                .any(|metric| {
+                    let labels = metric.get_label();
+                    labels.iter().any(|l| l.get_name() == "stream" && l.get_value() == stream)
+                        && labels.iter().any(|l| l.get_name() == "value" && l.get_value() == value)
+                })
+        };
+        // missing and invalid tokens
+        let res = warp::test::request().path("/api/metadata/my-stream").filter(&api).await;
+        assert!(res.is_err());
+        let res = request("GET", "").header("authorization", "Bearer wrong").filter(&api).await;
+        assert!(res.unwrap_err().find::<Unauthorized>().is_some());
+        // unknown streams
+        let res = request("GET", "").reply(&api).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        // unknown fields
+        let res = request("PUT", r#"{"region": "eu"}"#).reply(&api).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        // set, get and remove metadata
+        let res = request("PUT", r#"{"customer": "acme"}"#).reply(&api).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            ctx.read().await.metadata.get_values_for("live", "my-stream").unwrap(),
+            vec!["acme"]
+        );
+        assert!(exported(&*ctx.read().await, "my-stream", "acme"));
+        let res = request("GET", "").reply(&api).await;
+        assert_eq!(res.body(), r#"{"customer":"acme"}"#);
+        let res = request("GET", "").path("/api/metadata").reply(&api).await;
+        assert_eq!(res.body(), r#"["my-stream"]"#);
+        let res = request("DELETE", "").reply(&api).await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert!(ctx.read().await.metadata.for_stream("my-stream").is_none());
+        assert!(!exported(&*ctx.read().await, "my-stream", "acme"));
+        // escaped stream names
+        let res = request("PUT", r#"{"customer": "acme"}"#)
+            .path("/api/metadata/my%20stream")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(ctx.read().await.metadata.for_stream("my stream").is_some());
+        // application-scoped streams
+        let res = request("PUT", r#"{"customer": "acme"}"#)
+            .path("/api/metadata/live/my-stream")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(ctx.read().await.metadata.for_stream("live/my-stream").is_some());
+        // changes are written back to the metadata file
+        let path = env::temp_dir()
+            .join(format!("nginx-rtmp-exporter-test-api-{}.json", std::process::id()));
+        let source = Arc::new(MetaSource::File(path.clone(), Format::Json));
+        let api = routes(ctx.clone(), Some("secret".to_owned()), Some(source));
+        let res = request("PUT", r#"{"customer": "globex"}"#).reply(&api).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let saved = MetaFile::from_path(&path, Format::Json);
+        fs::remove_file(&path).ok();
+        assert_eq!(saved.unwrap().for_stream("my-stream").unwrap()["customer"], "globex");
+        // the API is disabled without a token
+        let api = routes(ctx, None, None);
+        let res = request("GET", "").filter(&api).await;
+        assert!(res.unwrap_err().is_not_found());
+    }
+}
@@ -0,0 +1,233 @@
+//! Handles loading the configuration file of the exporter.
+use std::{
+    fmt, fs,
+    net::IpAddr,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use prometheus::proto::MetricFamily;
+use regex::Regex;
+use reqwest::{Client, Url};
+use serde::{Deserialize, Deserializer};
+
+use crate::{
+    context::Target,
+    meta::{Format, MetaFile},
+    rules::ClientRule,
+};
+
+/// The configuration file of the exporter. Every setting is optional, and is
+/// overridden by the matching command line flag or environment variable.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// The host to listen on.
+    pub host: Option<IpAddr>,
+    /// The port to listen on.
+    pub port: Option<u16>,
+    /// The RTMP statistics endpoints of NGINX to scrape.
+    pub targets: Vec<Target>,
+    /// How long, in seconds, to wait for a target to respond.
+    pub scrape_timeout: Option<u64>,
+    /// How long, in seconds, a stream may be missing from the statistics
+    /// before its series are removed.
+    pub stream_grace_period: Option<u64>,
+    /// The maximum number of clients per stream to export per-client metrics
+    /// for.
+    pub max_clients_per_stream: Option<usize>,
+    /// Which metrics to export.
+    pub metrics: MetricFilter,
+    /// The rules deciding the role of each client.
+    pub client_rules: Vec<ClientRule>,
+    /// The metadata to apply to streams.
+    pub metadata: Option<MetaFile>,
+    /// A URL to periodically fetch the metadata from, instead of the metadata
+    /// in this file.
+    pub metadata_url: Option<Url>,
+    /// How long, in seconds, to wait between fetches of the metadata URL.
+    pub metadata_refresh_interval: Option<u64>,
+    /// The bearer token required by the metadata API. The API is disabled if
+    /// no token is set.
+    pub api_token: Option<String>,
+    /// Whether changes made through the metadata API are written back to the
+    /// metadata file.
+    pub persist_metadata: Option<bool>,
+    /// Whether the metadata can be reloaded with a `POST /-/reload` request.
+    pub enable_reload: Option<bool>,
+}
+
+impl Config {
+    /// Load a configuration file, in YAML if its extension is `.yaml` or
+    /// `.yml`, and in TOML otherwise.
+    pub fn from_path(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let file = fs::read_to_string(path).context("Failed to read config file")?;
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("yaml" | "yml") => {
+                serde_yaml::from_str(&file).context("Failed to parse config file")
+            }
+            _ => toml::from_str(&file).context("Failed to parse config file"),
+        }
+    }
+}
+
+/// Where the metadata of streams is loaded from, so that it can be reloaded.
+#[derive(Debug)]
+pub enum MetaSource {
+    /// A metadata file.
+    File(PathBuf, Format),
+    /// The metadata section of a configuration file.
+    Config(PathBuf),
+    /// The environment variables of the exporter.
+    Env,
+    /// A document fetched from a URL, in the format given by the extension of
+    /// its path, or JSON otherwise.
+    Http(Url),
+    /// No metadata.
+    None,
+}
+
+impl MetaSource {
+    /// Load the metadata from this source, using the given client to fetch it
+    /// from a URL.
+    pub async fn load(&self, http: &Client) -> Result<MetaFile> {
+        match self {
+            MetaSource::File(path, format) => MetaFile::from_path(path, *format),
+            MetaSource::Config(path) => Ok(Config::from_path(path)?.metadata.unwrap_or_default()),
+            MetaSource::Env => MetaFile::from_env(),
+            MetaSource::Http(url) => {
+                let res = http.get(url.clone()).send().await?.error_for_status()?;
+                let file = res.text().await.context("Failed to read meta document")?;
+                MetaFile::parse(&file, Format::detect(url.path()))
+            }
+            MetaSource::None => Ok(MetaFile::default()),
+        }
+    }
+
+    /// Write the metadata back to this source. Only metadata files can be
+    /// written to.
+    pub fn save(&self, metadata: &MetaFile) -> Result<()> {
+        match self {
+            MetaSource::File(path, format) => metadata.save(path, *format),
+            _ => bail!("Metadata can only be saved to a metadata file"),
+        }
+    }
+}
+
+impl fmt::Display for MetaSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MetaSource::File(path, _) => write!(f, "{}", path.display()),
+            MetaSource::Config(path) => write!(f, "config file {}", path.display()),
+            MetaSource::Env => write!(f, "environment"),
+            MetaSource::Http(url) => write!(f, "{}", url),
+            MetaSource::None => write!(f, "nowhere"),
+        }
+    }
+}
+
+/// A filter deciding which metric families are exported, by name.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricFilter {
+    /// Patterns of metric names to export. All metrics are exported if empty.
+    #[serde(deserialize_with = "deserialize_regexes")]
+    pub include: Vec<Regex>,
+    /// Patterns of metric names not to export, even if they are included.
+    #[serde(deserialize_with = "deserialize_regexes")]
+    pub exclude: Vec<Regex>,
+}
+
+impl MetricFilter {
+    /// This method checks if the metric with the given name should be exported.
+    pub fn allows(&self, name: &str) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|pattern| pattern.is_match(name)))
+            && !self.exclude.iter().any(|pattern| pattern.is_match(name))
+    }
+
+    /// Remove the metric families which should not be exported.
+    pub fn apply(&self, families: Vec<MetricFamily>) -> Vec<MetricFamily> {
+        families.into_iter().filter(|family| self.allows(family.get_name())).collect()
+    }
+}
+
+fn deserialize_regexes<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Regex>, D::Error> {
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        // match whole metric names, rather than any part of them
+        .map(|pattern| Regex::new(&format!("^(?:{})$", pattern)).map_err(serde::de::Error::custom))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Config;
+
+    #[test]
+    fn test_parse_config_toml() {
+        let file = r#"
+host = "0.0.0.0"
+port = 9200
+targets = ["http://ingest-1/stat", "edge=http://edge-1/stat"]
+scrape_timeout = 5
+
+[metrics]
+exclude = ["nginx_rtmp_client_.*"]
+
+[[client_rules]]
+role = "local_relay"
+address = ["fd00::/8"]
+
+[metadata]
+fields = ["message"]
+
+[metadata.metadata.my-stream]
+message = "hello"
+"#;
+        let config: Config = toml::from_str(file).expect("failed to parse config file");
+        assert_eq!(config.host, Some("0.0.0.0".parse().unwrap()));
+        assert_eq!(config.port, Some(9200));
+        assert_eq!(config.targets.len(), 2);
+        assert_eq!(config.targets[1].name, "edge");
+        assert_eq!(config.scrape_timeout, Some(5));
+        assert_eq!(config.stream_grace_period, None);
+        assert_eq!(config.client_rules.len(), 1);
+        let metadata = config.metadata.expect("config has no metadata");
+        assert_eq!(metadata.get_values_for("live", "my-stream").unwrap(), vec!["hello"]);
+    }
+
+    #[test]
+    fn test_parse_config_yaml() {
+        let file = r#"
+targets:
+  - http://ingest-1/stat
+stream_grace_period: 30
+metrics:
+  include: ["nginx_rtmp_stream_.*"]
+"#;
+        let config: Config = serde_yaml::from_str(file).expect("failed to parse config file");
+        assert_eq!(config.targets[0].name, "ingest-1");
+        assert_eq!(config.stream_grace_period, Some(30));
+        assert!(config.metadata.is_none());
+    }
+
+    #[test]
+    fn test_parse_config_invalid() {
+        assert!(toml::from_str::<Config>("scrape_url = \"http://ingest-1/stat\"").is_err());
+        assert!(toml::from_str::<Config>("targets = [\"ingest-1\"]").is_err());
+    }
+
+    #[test]
+    fn test_metric_filter() {
+        let config: Config = toml::from_str(
+            "[metrics]\ninclude = [\"nginx_rtmp_.*\"]\nexclude = [\"nginx_rtmp_client_.*\"]",
+        )
+        .unwrap();
+        assert!(config.metrics.allows("nginx_rtmp_up"));
+        assert!(!config.metrics.allows("nginx_rtmp_client_info"));
+        assert!(!config.metrics.allows("nginx_build_info"));
+        // patterns match whole names
+        assert!(!config.metrics.allows("my_nginx_rtmp_up"));
+    }
+}
@@ -1,99 +1,420 @@
-use std::time::Duration;
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::{Mutex, PoisonError},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
-use anyhow::{Context as AnyhowContext, Result};
+use anyhow::{bail, Context as AnyhowContext, Result};
+use futures::future::join_all;
+use prometheus::{proto::MetricFamily, Registry};
 use reqwest::{Client, Url};
-use tracing::{debug, trace, warn};
+use serde::{Deserialize, Deserializer};
+use tracing::{debug, info, warn};
 
-use crate::{meta::MetaFile, metrics::MetricContext};
+use crate::{
+    meta::MetaFile,
+    metrics::{label_values, ExporterInfo, MetadataStatus, MetricContext},
+    rules::ClientRules,
+    state::{client_key, stream_key, ApplicationKey, TargetState},
+    xml::{fetch_rtmp_stats, ClientRole, RtmpStats, RtmpStream},
+    Settings,
+};
+
+/// A named NGINX RTMP statistics endpoint to scrape.
+#[derive(Debug, Clone)]
+pub struct Target {
+    /// The name of this target, exported as the `target` label.
+    pub name: String,
+    /// The RTMP statistics endpoint of this target.
+    pub url: Url,
+}
+
+impl FromStr for Target {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // bare URLs are named after their host and port
+        if let Ok(url) = Url::parse(s) {
+            let name = match (url.host_str(), url.port()) {
+                (Some(host), Some(port)) => format!("{}:{}", host, port),
+                (Some(host), None) => host.to_owned(),
+                _ => bail!("Target URL has no host: {}", s),
+            };
+            return Ok(Target { name, url });
+        }
+        // otherwise expect a `name=url` pair
+        let (name, url) = match s.split_once('=') {
+            Some((name, url)) if !name.is_empty() => (name, url),
+            _ => bail!("Invalid target, expected <URL> or <NAME>=<URL>: {}", s),
+        };
+        let url = Url::parse(url).with_context(|| format!("Invalid target URL: {}", url))?;
+        Ok(Target { name: name.to_owned(), url })
+    }
+}
+
+impl<'de> Deserialize<'de> for Target {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// The outcome of fetching the stats of a target.
+#[derive(Debug)]
+pub struct Scrape {
+    pub target: Target,
+    /// How long the scrape took.
+    pub duration: Duration,
+    pub stats: Result<RtmpStats>,
+}
+
+impl Scrape {
+    /// Fetch the stats of a target, timing how long the scrape took. This does
+    /// not borrow the context, so that it is not locked while waiting on the
+    /// network.
+    pub async fn fetch(http: &Client, target: Target) -> Self {
+        let start = Instant::now();
+        let stats = fetch_rtmp_stats(http, &target.url).await;
+        Self { target, duration: start.elapsed(), stats }
+    }
+
+    /// Fetch the stats of every target in parallel.
+    pub async fn fetch_all(http: &Client, targets: &[Target]) -> Vec<Self> {
+        join_all(targets.iter().map(|target| Self::fetch(http, target.clone()))).await
+    }
+}
+
+/// The kind of error that caused a scrape to fail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScrapeErrorKind {
+    /// The request failed, or NGINX responded with an error status.
+    Http,
+    /// The request timed out.
+    Timeout,
+    /// The response could not be parsed.
+    Parse,
+}
+
+impl ScrapeErrorKind {
+    /// All kinds of scrape error.
+    pub const ALL: [ScrapeErrorKind; 3] =
+        [ScrapeErrorKind::Http, ScrapeErrorKind::Timeout, ScrapeErrorKind::Parse];
+
+    /// Classify the error returned by a scrape.
+    pub fn of(err: &anyhow::Error) -> Self {
+        match err.downcast_ref::<reqwest::Error>() {
+            Some(err) if err.is_timeout() => ScrapeErrorKind::Timeout,
+            Some(_) => ScrapeErrorKind::Http,
+            None => ScrapeErrorKind::Parse,
+        }
+    }
+
+    /// The value of the `kind` label for this error.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ScrapeErrorKind::Http => "http",
+            ScrapeErrorKind::Timeout => "timeout",
+            ScrapeErrorKind::Parse => "parse",
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct Context {
     pub http: Client,
     pub metadata: MetaFile,
+    /// The registry of the exporter and NGINX metrics, which is replaced if
+    /// the metadata fields change.
+    pub registry: Registry,
+    pub exporter_info: ExporterInfo,
     pub metrics: MetricContext,
-    pub rtmp_stats_endpoint: Url,
+    /// The freshness of the metadata.
+    pub metadata_status: MetadataStatus,
+    pub targets: Vec<Target>,
+    /// The state of each target as of its last successful scrape, behind its
+    /// own lock so that scrapes can be recorded through a shared context.
+    pub states: Mutex<HashMap<String, TargetState>>,
+    /// How long a stream may be missing from the stats before its series are
+    /// removed.
+    pub stream_grace_period: Duration,
+    /// The maximum number of clients per stream to export per-client series
+    /// for. Per-client series are disabled if this is zero.
+    pub max_clients_per_stream: usize,
+    /// The rules deciding the role of each client.
+    pub client_rules: ClientRules,
 }
 
 impl Context {
-    pub fn new(endpoint: Url, metadata: MetaFile) -> Result<Self> {
-        let metrics =
-            MetricContext::from_metadata(&metadata).context("failed to create MetricContext")?;
+    /// Create the context of the exporter from its settings.
+    pub fn new(settings: &Settings) -> Result<Self> {
+        let targets = &settings.targets;
+        // check target names are unique, as they are used to label metrics
+        for (i, target) in targets.iter().enumerate() {
+            if targets[..i].iter().any(|other| other.name == target.name) {
+                bail!("Duplicate target name: {}", target.name);
+            }
+        }
+        let metadata = settings.metadata.clone();
+        metadata.validate().context("invalid metadata")?;
+        let (registry, exporter_info, metrics) = Self::register_metrics(&metadata)?;
+        let metadata_status =
+            MetadataStatus::new().context("failed to register metadata status metrics")?;
         // create context
         Ok(Self {
             http: reqwest::Client::builder()
-                .timeout(Duration::from_secs(3))
+                .timeout(settings.scrape_timeout)
                 .build()
                 .expect("failed to build reqwest client"),
             metadata,
+            registry,
+            exporter_info,
             metrics,
-            rtmp_stats_endpoint: endpoint,
+            metadata_status,
+            targets: targets.clone(),
+            states: Mutex::new(HashMap::new()),
+            stream_grace_period: settings.stream_grace_period,
+            max_clients_per_stream: settings.max_clients_per_stream,
+            client_rules: settings.client_rules.clone(),
         })
     }
 
-    pub async fn collect_metrics(&mut self) {
+    /// Register the exporter and NGINX metrics for the given metadata in a new
+    /// registry.
+    fn register_metrics(metadata: &MetaFile) -> Result<(Registry, ExporterInfo, MetricContext)> {
+        let registry = Registry::new();
+        let exporter_info = MetricContext::register_exporter_info(&registry, metadata)
+            .context("failed to register exporter metrics")?;
+        let metrics = MetricContext::from_metadata(&registry, metadata)
+            .context("failed to create MetricContext")?;
+        Ok((registry, exporter_info, metrics))
+    }
+
+    /// Replace the metadata with newly loaded metadata, if it is valid. If the
+    /// metadata fields have changed, the metrics are registered again with the
+    /// new label sets, starting every series afresh.
+    pub fn reload_metadata(&mut self, metadata: Result<MetaFile>) -> Result<()> {
+        let result = metadata.and_then(|metadata| self.replace_metadata(metadata));
+        self.metadata_status.record(result.is_ok());
+        result
+    }
+
+    /// Gather the exporter and NGINX metrics.
+    pub fn gather(&self) -> Vec<MetricFamily> {
+        let mut metric_families = self.registry.gather();
+        metric_families.extend(self.metadata_status.registry.gather());
+        metric_families
+    }
+
+    fn replace_metadata(&mut self, metadata: MetaFile) -> Result<()> {
+        metadata.validate().context("invalid metadata")?;
+        if metadata.get_fields() != self.metadata.get_fields()
+            || metadata.global_fields != self.metadata.global_fields
+        {
+            info!("metadata fields changed, registering metrics again");
+            let (registry, exporter_info, metrics) = Self::register_metrics(&metadata)?;
+            self.registry = registry;
+            self.exporter_info = exporter_info;
+            self.metrics = metrics;
+            self.states.get_mut().unwrap_or_else(PoisonError::into_inner).clear();
+        } else {
+            self.exporter_info.set_metadata(&metadata);
+        }
+        self.metadata = metadata;
+        Ok(())
+    }
+
+    /// Record the scrapes of every target. Errors are handled per target, so
+    /// one failing target does not blank the others - the series of a failed
+    /// target are kept, and `nginx_rtmp_up` reports the failure.
+    pub fn collect_metrics(&self, scrapes: Vec<Scrape>) {
         debug!("collecting metrics...");
-        // reset all metrics to prevent stale data
-        // TODO: use existing metrics to remove extraneous labels
-        trace!("resetting metrics...");
-        self.metrics.nginx_build_info.reset();
-        self.metrics.nginx_rtmp_incoming_bytes_total.set(0);
-        self.metrics.nginx_rtmp_outgoing_bytes_total.set(0);
-        self.metrics.nginx_rtmp_incoming_bandwidth.set(0);
-        self.metrics.nginx_rtmp_outgoing_bandwidth.set(0);
-        self.metrics.nginx_rtmp_stream_bandwidth_audio.reset();
-        self.metrics.nginx_rtmp_stream_bandwidth_video.reset();
-        self.metrics.nginx_rtmp_stream_incoming_bandwidth.reset();
-        self.metrics.nginx_rtmp_stream_outgoing_bandwidth.reset();
-        self.metrics.nginx_rtmp_stream_incoming_bytes_total.reset();
-        self.metrics.nginx_rtmp_stream_outgoing_bytes_total.reset();
-        self.metrics.nginx_rtmp_stream_publisher_avsync.reset();
-        self.metrics.nginx_rtmp_stream_total_clients.reset();
-        // fetch stats and handle errors
-        let stats = self.fetch_rtmp_stats().await;
-        if let Err(err) = stats {
-            warn!("failed to fetch RTMP stats: {}", err);
-            return;
-        }
-        let stats = stats.unwrap();
-        // hydrate build info metric
-        self.metrics
-            .nginx_build_info
-            .get_metric_with_label_values(&[
-                &stats.nginx_version,
-                &stats.compiler,
-                &stats.nginx_rtmp_version,
-            ])
-            .unwrap()
-            .set(1);
+        let mut states = self.states.lock().unwrap_or_else(PoisonError::into_inner);
+        for scrape in scrapes {
+            let state = states.entry(scrape.target.name.clone()).or_default();
+            self.record_scrape(&self.metrics, state, scrape);
+        }
+    }
+
+    /// Resolve the target of a probe request, which is either the name of a
+    /// configured target or a target in the form accepted by `--target`.
+    pub fn resolve_probe_target(&self, target: &str) -> Result<Target> {
+        match self.targets.iter().find(|configured| configured.name == target) {
+            Some(configured) => Ok(configured.clone()),
+            None => target.parse(),
+        }
+    }
+
+    /// Record the scrape of a single target into a fresh registry, for use by
+    /// the `/probe` endpoint.
+    pub fn probe(&self, scrape: Scrape) -> Result<Registry> {
+        let registry = Registry::new();
+        let metrics = MetricContext::from_metadata(&registry, &self.metadata)
+            .context("failed to create MetricContext")?;
+        // probes start from a fresh state, so counters report NGINX's own totals
+        let mut state = TargetState::default();
+        self.record_scrape(&metrics, &mut state, scrape);
+        Ok(registry)
+    }
+
+    /// Record the outcome of a scrape, including the health of the target.
+    fn record_scrape(&self, metrics: &MetricContext, state: &mut TargetState, scrape: Scrape) {
+        let Scrape { target, duration, stats } = scrape;
+        let target = target.name.as_str();
+        metrics
+            .nginx_rtmp_scrape_duration_seconds
+            .with_label_values(&[target])
+            .set(duration.as_secs_f64());
+        // make sure every kind of error is exported, even before it first occurs
+        for kind in ScrapeErrorKind::ALL {
+            metrics.nginx_rtmp_scrape_errors_total.with_label_values(&[target, kind.as_str()]);
+        }
+        match stats {
+            Ok(stats) => {
+                metrics.nginx_rtmp_up.with_label_values(&[target]).set(1);
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+                metrics
+                    .nginx_rtmp_last_successful_scrape_timestamp_seconds
+                    .with_label_values(&[target])
+                    .set(now.as_secs_f64());
+                self.record_stats(metrics, state, target, &stats);
+            }
+            Err(err) => {
+                let kind = ScrapeErrorKind::of(&err);
+                warn!(target, kind = kind.as_str(), "failed to fetch RTMP stats: {:#}", err);
+                metrics.nginx_rtmp_up.with_label_values(&[target]).set(0);
+                metrics
+                    .nginx_rtmp_scrape_errors_total
+                    .with_label_values(&[target, kind.as_str()])
+                    .inc();
+            }
+        }
+    }
+
+    /// Record the statistics of a single target.
+    fn record_stats(
+        &self,
+        metrics: &MetricContext,
+        state: &mut TargetState,
+        target: &str,
+        stats: &RtmpStats,
+    ) {
+        let now = Instant::now();
+        // count elements that were skipped while parsing
+        for (path, err) in stats.parse_errors() {
+            warn!(target, path, "skipped malformed element: {}", err);
+            metrics.nginx_rtmp_exporter_parse_errors_total.with_label_values(&[target, path]).inc();
+        }
+        // hydrate build info metric, removing the old series if NGINX was upgraded
+        let lbs = [target, &stats.nginx_version, &stats.compiler, &stats.nginx_rtmp_version];
+        metrics.nginx_build_info.get_metric_with_label_values(&lbs).unwrap().set(1);
+        let owned = lbs.iter().map(|s| s.to_string()).collect();
+        if let Some(previous) = state.build_info.observe((), owned, now) {
+            metrics.nginx_build_info.remove_label_values(&label_values(&previous)).ok();
+        }
         // set root-level metrics
-        self.metrics.nginx_rtmp_incoming_bytes_total.set(stats.bytes_in as i64);
-        self.metrics.nginx_rtmp_outgoing_bytes_total.set(stats.bytes_out as i64);
-        self.metrics.nginx_rtmp_incoming_bandwidth.set(stats.bw_in as i64);
-        self.metrics.nginx_rtmp_outgoing_bandwidth.set(stats.bw_out as i64);
-        // iterate through streams and set stats
-        stats.server.applications.iter().for_each(|application| {
+        let delta = state.observe_server(stats);
+        if delta.restarted {
+            warn!(target, pid = stats.pid, "NGINX has restarted since the last scrape");
+        }
+        metrics
+            .nginx_rtmp_restarts_total
+            .with_label_values(&[target])
+            .inc_by(delta.restarted as u64);
+        metrics.nginx_rtmp_uptime_seconds.with_label_values(&[target]).set(stats.uptime as i64);
+        let start_time = SystemTime::now()
+            .checked_sub(Duration::from_secs(stats.uptime as u64))
+            .and_then(|start| start.duration_since(UNIX_EPOCH).ok())
+            .unwrap_or_default();
+        metrics
+            .nginx_rtmp_start_time_seconds
+            .with_label_values(&[target])
+            .set(start_time.as_secs() as f64);
+        metrics
+            .nginx_rtmp_connections_accepted_total
+            .with_label_values(&[target])
+            .inc_by(delta.accepted);
+        metrics
+            .nginx_rtmp_incoming_bytes_total
+            .with_label_values(&[target])
+            .inc_by(delta.bytes.bytes_in);
+        metrics
+            .nginx_rtmp_outgoing_bytes_total
+            .with_label_values(&[target])
+            .inc_by(delta.bytes.bytes_out);
+        metrics.nginx_rtmp_incoming_bandwidth.with_label_values(&[target]).set(stats.bw_in as i64);
+        metrics.nginx_rtmp_outgoing_bandwidth.with_label_values(&[target]).set(stats.bw_out as i64);
+        metrics
+            .nginx_rtmp_application_count
+            .with_label_values(&[target])
+            .set(stats.servers.iter().map(|server| server.applications.len()).sum::<usize>() as i64);
+        // iterate through the applications of every server block, which are
+        // identified by the index of their server block
+        let applications = stats.servers.iter().enumerate().flat_map(|(index, server)| {
+            server.applications.iter().map(move |application| (index.to_string(), application))
+        });
+        applications.for_each(|(server, application)| {
+            let key: ApplicationKey = (server.clone(), application.name.clone());
+            state.applications.observe(
+                key.clone(),
+                vec![target.to_owned(), server.clone(), application.name.clone()],
+                now,
+            );
+            // set application info, removing the old series if its sections changed
+            let lbs = [
+                target,
+                server.as_str(),
+                application.name.as_str(),
+                if application.live.is_some() { "true" } else { "false" },
+                if application.play.is_some() { "true" } else { "false" },
+                if application.record.is_some() { "true" } else { "false" },
+            ];
+            metrics.nginx_rtmp_application_info.with_label_values(&lbs).set(1);
+            let owned = lbs.iter().map(|s| s.to_string()).collect();
+            if let Some(previous) = state.application_info.observe(key.clone(), owned, now) {
+                metrics
+                    .nginx_rtmp_application_info
+                    .remove_label_values(&label_values(&previous))
+                    .ok();
+            }
+            // set play metrics, if this application plays streams from disk or HTTP
+            let app_lbs = [target, server.as_str(), application.name.as_str()];
+            match &application.play {
+                Some(play) => {
+                    metrics
+                        .nginx_rtmp_play_active_streams
+                        .with_label_values(&app_lbs)
+                        .set(play.streams.len() as i64);
+                    metrics
+                        .nginx_rtmp_play_clients
+                        .with_label_values(&app_lbs)
+                        .set(play.nclients as i64);
+                    metrics
+                        .nginx_rtmp_play_outgoing_bytes_total
+                        .with_label_values(&app_lbs)
+                        .inc_by(state.observe_play(&key, play));
+                }
+                None => metrics.remove_play(&app_lbs),
+            }
             // set active streams
-            self.metrics
-                .nginx_rtmp_active_streams
-                .with_label_values(&[application.name.as_str()])
-                .set(
-                    application
-                        .live
-                        .streams
-                        .iter()
-                        // ignore streams with no metadata defined
-                        .filter(|stream| stream.meta.is_some())
-                        // ignore streams that are only used as relays
-                        .filter(|stream| {
-                            stream.clients.iter().any(|client| !client.is_local_relay())
+            metrics.nginx_rtmp_active_streams.with_label_values(&app_lbs).set(
+                application
+                    .live_streams()
+                    .iter()
+                    // ignore streams with no metadata defined
+                    .filter(|stream| stream.meta.is_some())
+                    // ignore streams that are only used as relays
+                    .filter(|stream| {
+                        stream.clients.iter().any(|client| {
+                            self.client_rules.classify(client) != ClientRole::LocalRelay
                         })
-                        .count() as i64,
-                );
+                    })
+                    .count() as i64,
+            );
             // iterate over application streams
-            application.live.streams.iter().for_each(|stream| {
+            application.live_streams().iter().for_each(|stream| {
                 debug!("resolving information for stream {}", stream.name);
                 // label values
-                let mut lbs = vec![application.name.as_str(), stream.name.as_str()];
+                let mut lbs =
+                    vec![target, server.as_str(), application.name.as_str(), stream.name.as_str()];
 
                 // if let Some(globals) = &self.metadata.global_fields {
                 //     globals.keys().for_each(|key| {
@@ -102,70 +423,573 @@ impl Context {
                 // }
 
                 // collect and append metadata values
-                let meta = self.metadata.get_values_for(&stream.name);
+                let meta = match self.metadata.get_values_for(&application.name, &stream.name) {
+                    Some(meta) => meta,
+                    None => {
+                        debug!("skipping stream {} with missing metadata", stream.name);
+                        return;
+                    }
+                };
                 let mut meta: Vec<&str> = meta.iter().map(|s| &**s).collect();
                 lbs.append(&mut meta);
                 let lbs = &lbs;
 
-                // incoming bytes
-                let incoming_bytes = self
-                    .metrics
+                // track the series of this stream, removing the old series if its
+                // labels have changed
+                let owned = lbs.iter().map(|s| s.to_string()).collect();
+                if let Some(previous) =
+                    state.stream_series.observe(stream_key(&key, &stream.name), owned, now)
+                {
+                    metrics.remove_stream(&label_values(&previous));
+                }
+
+                // incoming and outgoing bytes
+                let delta = state.observe_stream(&key, stream);
+                let incoming_bytes = metrics
                     .nginx_rtmp_stream_incoming_bytes_total
                     .get_metric_with_label_values(lbs)
                     .unwrap();
-                incoming_bytes.set(stream.bytes_in as i64);
-
-                // outgoing bytes
-                let outgoing_bytes = self
-                    .metrics
+                incoming_bytes.inc_by(delta.bytes_in);
+                let outgoing_bytes = metrics
                     .nginx_rtmp_stream_outgoing_bytes_total
                     .get_metric_with_label_values(lbs)
                     .unwrap();
-                outgoing_bytes.set(stream.bytes_out as i64);
+                outgoing_bytes.inc_by(delta.bytes_out);
 
                 // incoming bandwidth
-                self.metrics
+                metrics
                     .nginx_rtmp_stream_incoming_bandwidth
                     .with_label_values(lbs)
                     .set(stream.bw_in as i64);
 
                 // outgoing bandwidth
-                self.metrics
+                metrics
                     .nginx_rtmp_stream_outgoing_bandwidth
                     .with_label_values(lbs)
                     .set(stream.bw_out as i64);
 
                 // video bandwidth
-                self.metrics
+                metrics
                     .nginx_rtmp_stream_bandwidth_video
                     .with_label_values(lbs)
                     .set(stream.bw_video as i64);
 
                 // audio bandwidth
-                self.metrics
+                metrics
                     .nginx_rtmp_stream_bandwidth_audio
                     .with_label_values(lbs)
                     .set(stream.bw_audio as i64);
 
                 // avsync
                 // if this stream includes audio, set avsync
-                if stream.bw_audio != 0 {
-                    if let Some(client) =
-                        stream.clients.iter().find(|client| client.publishing.is_some())
-                    {
-                        self.metrics
+                let publisher = stream
+                    .clients
+                    .iter()
+                    .find(|client| self.client_rules.classify(client) == ClientRole::Publisher);
+                match publisher {
+                    Some(client) if stream.bw_audio != 0 => {
+                        metrics
                             .nginx_rtmp_stream_publisher_avsync
                             .with_label_values(lbs)
                             .set(client.avsync);
                     }
+                    _ => {
+                        metrics.nginx_rtmp_stream_publisher_avsync.remove_label_values(lbs).ok();
+                    }
                 }
-                // connected clients
-                // total clients - 1, 1 publisher
-                self.metrics
+                // connected clients, by role
+                let roles: Vec<ClientRole> = stream
+                    .clients
+                    .iter()
+                    .map(|client| self.client_rules.classify(client))
+                    .collect();
+                let publishers =
+                    roles.iter().filter(|role| **role == ClientRole::Publisher).count();
+                let viewers = roles.iter().filter(|role| **role == ClientRole::Viewer).count();
+                let relays = roles.iter().filter(|role| role.is_relay()).count();
+                metrics.nginx_rtmp_stream_publishers.with_label_values(lbs).set(publishers as i64);
+                metrics.nginx_rtmp_stream_viewers.with_label_values(lbs).set(viewers as i64);
+                metrics.nginx_rtmp_stream_relays.with_label_values(lbs).set(relays as i64);
+                // relays are not counted as clients, whichever direction they relay
+                metrics
                     .nginx_rtmp_stream_total_clients
                     .with_label_values(lbs)
-                    .set((stream.clients.len() - 1) as i64);
+                    .set((publishers + viewers) as i64);
+
+                // codec metadata
+                self.record_stream_meta(metrics, state, lbs, &key, stream, now);
+
+                // recorders
+                self.record_recorders(metrics, state, lbs, &key, stream, now);
+
+                // relays
+                self.record_relays(metrics, state, lbs, &key, stream, now);
+
+                // per-client series, if enabled
+                if self.max_clients_per_stream > 0 {
+                    self.record_clients(metrics, state, lbs, &key, stream, now);
+                }
             })
         });
+        // remove the series of clients, streams and applications which have gone away
+        for labels in state.expire_clients(now, Duration::ZERO) {
+            metrics.remove_client(&label_values(&labels));
+        }
+        for (_, labels) in state.client_info.expire(now, Duration::ZERO) {
+            metrics.nginx_rtmp_client_info.remove_label_values(&label_values(&labels)).ok();
+        }
+        for labels in state.expire_relays(now, Duration::ZERO) {
+            metrics.remove_relay(&label_values(&labels));
+        }
+        for labels in state.expire_streams(now, self.stream_grace_period) {
+            debug!("removing series for stream {}", labels[3]);
+            metrics.remove_stream(&label_values(&labels));
+        }
+        for (_, labels) in state.video_info.expire(now, self.stream_grace_period) {
+            metrics.nginx_rtmp_stream_video_info.remove_label_values(&label_values(&labels)).ok();
+        }
+        for (_, labels) in state.audio_info.expire(now, self.stream_grace_period) {
+            metrics.nginx_rtmp_stream_audio_info.remove_label_values(&label_values(&labels)).ok();
+        }
+        for (_, labels) in state.recorders.expire(now, self.stream_grace_period) {
+            metrics.remove_recorder(&label_values(&labels));
+        }
+        for labels in state.expire_applications(now, self.stream_grace_period) {
+            metrics.nginx_rtmp_active_streams.remove_label_values(&label_values(&labels)).ok();
+            metrics.remove_play(&label_values(&labels));
+        }
+        for (_, labels) in state.application_info.expire(now, self.stream_grace_period) {
+            metrics.nginx_rtmp_application_info.remove_label_values(&label_values(&labels)).ok();
+        }
+    }
+
+    /// Record the video and audio metadata of a stream, removing its series if
+    /// the metadata is no longer available.
+    fn record_stream_meta(
+        &self,
+        metrics: &MetricContext,
+        state: &mut TargetState,
+        lbs: &[&str],
+        application: &ApplicationKey,
+        stream: &RtmpStream,
+        now: Instant,
+    ) {
+        let key = stream_key(application, &stream.name);
+        let meta = stream.meta.as_ref();
+        // video dimensions
+        match meta.map(|meta| &meta.video) {
+            Some(video) => {
+                metrics
+                    .nginx_rtmp_stream_video_width_pixels
+                    .with_label_values(lbs)
+                    .set(video.width as i64);
+                metrics
+                    .nginx_rtmp_stream_video_height_pixels
+                    .with_label_values(lbs)
+                    .set(video.height as i64);
+                metrics
+                    .nginx_rtmp_stream_video_frame_rate
+                    .with_label_values(lbs)
+                    .set(video.frame_rate as f64);
+            }
+            None => metrics.remove_video(lbs),
+        }
+        // video info, which is only present if NGINX knows the video codec
+        match meta.and_then(|meta| Some((&meta.video, meta.video.codec.as_deref()?))) {
+            Some((video, codec)) => {
+                let level = video.level.map(|level| level.to_string()).unwrap_or_default();
+                let mut info_lbs = lbs.to_vec();
+                info_lbs.extend([
+                    codec,
+                    video.profile.as_deref().unwrap_or_default(),
+                    level.as_str(),
+                ]);
+                metrics.nginx_rtmp_stream_video_info.with_label_values(&info_lbs).set(1);
+                let owned = info_lbs.iter().map(|s| s.to_string()).collect();
+                if let Some(previous) = state.video_info.observe(key.clone(), owned, now) {
+                    metrics
+                        .nginx_rtmp_stream_video_info
+                        .remove_label_values(&label_values(&previous))
+                        .ok();
+                }
+            }
+            None => {
+                if let Some(previous) = state.video_info.remove(&key) {
+                    metrics
+                        .nginx_rtmp_stream_video_info
+                        .remove_label_values(&label_values(&previous))
+                        .ok();
+                }
+            }
+        }
+        // audio, which is only present if NGINX knows its codec
+        match meta.map(|meta| &meta.audio).filter(|audio| audio.codec.is_some()) {
+            Some(audio) => {
+                match audio.channels {
+                    Some(channels) => metrics
+                        .nginx_rtmp_stream_audio_channels
+                        .with_label_values(lbs)
+                        .set(channels as i64),
+                    None => {
+                        metrics.nginx_rtmp_stream_audio_channels.remove_label_values(lbs).ok();
+                    }
+                }
+                match audio.sample_rate {
+                    Some(sample_rate) => metrics
+                        .nginx_rtmp_stream_audio_sample_rate_hertz
+                        .with_label_values(lbs)
+                        .set(sample_rate as i64),
+                    None => {
+                        metrics
+                            .nginx_rtmp_stream_audio_sample_rate_hertz
+                            .remove_label_values(lbs)
+                            .ok();
+                    }
+                }
+                let mut info_lbs = lbs.to_vec();
+                info_lbs.extend([
+                    audio.codec.as_deref().unwrap_or_default(),
+                    audio.profile.as_deref().unwrap_or_default(),
+                ]);
+                metrics.nginx_rtmp_stream_audio_info.with_label_values(&info_lbs).set(1);
+                let owned = info_lbs.iter().map(|s| s.to_string()).collect();
+                if let Some(previous) = state.audio_info.observe(key, owned, now) {
+                    metrics
+                        .nginx_rtmp_stream_audio_info
+                        .remove_label_values(&label_values(&previous))
+                        .ok();
+                }
+            }
+            None => {
+                metrics.remove_audio(lbs);
+                if let Some(previous) = state.audio_info.remove(&key) {
+                    metrics
+                        .nginx_rtmp_stream_audio_info
+                        .remove_label_values(&label_values(&previous))
+                        .ok();
+                }
+            }
+        }
+    }
+
+    /// Record the state of each recorder of a stream.
+    fn record_recorders(
+        &self,
+        metrics: &MetricContext,
+        state: &mut TargetState,
+        lbs: &[&str],
+        application: &ApplicationKey,
+        stream: &RtmpStream,
+        now: Instant,
+    ) {
+        for recorder in stream.recorders() {
+            let mut recorder_lbs = lbs.to_vec();
+            recorder_lbs.push(&recorder.id);
+            metrics
+                .nginx_rtmp_stream_recording
+                .with_label_values(&recorder_lbs)
+                .set(recorder.recording.is_some() as i64);
+            metrics
+                .nginx_rtmp_stream_recorded_bytes
+                .with_label_values(&recorder_lbs)
+                .set(recorder.bytes as i64);
+            let key = (stream_key(application, &stream.name), recorder.id.clone());
+            let owned = recorder_lbs.iter().map(|s| s.to_string()).collect();
+            if let Some(previous) = state.recorders.observe(key, owned, now) {
+                metrics.remove_recorder(&label_values(&previous));
+            }
+        }
+    }
+
+    /// Record the direction, peer and traffic of each relay of a stream.
+    fn record_relays(
+        &self,
+        metrics: &MetricContext,
+        state: &mut TargetState,
+        lbs: &[&str],
+        application: &ApplicationKey,
+        stream: &RtmpStream,
+        now: Instant,
+    ) {
+        for client in &stream.clients {
+            if !self.client_rules.classify(client).is_relay() {
+                continue;
+            }
+            let mut relay_lbs = lbs.to_vec();
+            relay_lbs.push(client.relay_direction().as_str());
+            relay_lbs.push(client.address.as_deref().unwrap_or_default());
+            let key = client_key(application, &stream.name, client.id);
+            let owned = relay_lbs.iter().map(|s| s.to_string()).collect();
+            if let Some(previous) = state.relay_series.observe(key, owned, now) {
+                metrics.remove_relay(&label_values(&previous));
+            }
+            metrics.nginx_rtmp_relay_info.with_label_values(&relay_lbs).set(1);
+            let delta = state.observe_relay(application, &stream.name, client);
+            metrics
+                .nginx_rtmp_relay_incoming_bytes_total
+                .with_label_values(&relay_lbs)
+                .inc_by(delta.bytes_in);
+            metrics
+                .nginx_rtmp_relay_outgoing_bytes_total
+                .with_label_values(&relay_lbs)
+                .inc_by(delta.bytes_out);
+        }
+    }
+
+    /// Record the per-client series of a stream, up to the configured number
+    /// of clients per stream. Publishers are recorded first.
+    fn record_clients(
+        &self,
+        metrics: &MetricContext,
+        state: &mut TargetState,
+        lbs: &[&str],
+        application: &ApplicationKey,
+        stream: &RtmpStream,
+        now: Instant,
+    ) {
+        let (publishers, others): (Vec<_>, Vec<_>) = stream
+            .clients
+            .iter()
+            .partition(|client| self.client_rules.classify(client) == ClientRole::Publisher);
+        for client in publishers.into_iter().chain(others).take(self.max_clients_per_stream) {
+            let id = client.id.to_string();
+            let key = client_key(application, &stream.name, client.id);
+            // client series
+            let mut client_lbs = lbs.to_vec();
+            client_lbs.push(&id);
+            let owned = client_lbs.iter().map(|s| s.to_string()).collect();
+            if let Some(previous) = state.client_series.observe(key.clone(), owned, now) {
+                metrics.remove_client(&label_values(&previous));
+            }
+            metrics
+                .nginx_rtmp_client_connected_seconds
+                .with_label_values(&client_lbs)
+                .set(client.time as f64 / 1000.0);
+            let dropped = state.observe_client(application, &stream.name, client);
+            metrics
+                .nginx_rtmp_client_dropped_frames_total
+                .with_label_values(&client_lbs)
+                .inc_by(dropped);
+            // client info
+            client_lbs.push(client.flashver.as_deref().unwrap_or_default());
+            client_lbs.push(client.pageurl.as_deref().unwrap_or_default());
+            client_lbs.push(self.client_rules.classify(client).as_str());
+            metrics.nginx_rtmp_client_info.with_label_values(&client_lbs).set(1);
+            let owned = client_lbs.iter().map(|s| s.to_string()).collect();
+            if let Some(previous) = state.client_info.observe(key, owned, now) {
+                metrics.nginx_rtmp_client_info.remove_label_values(&label_values(&previous)).ok();
+            }
+        }
+    }
+}
+
+/// Create a context scraping a single local target, for use by tests.
+#[cfg(test)]
+pub fn test_context(metadata: MetaFile, client_rules: ClientRules) -> Context {
+    Context::new(&Settings {
+        targets: vec!["http://localhost/stat".parse().unwrap()],
+        addr: ([127, 0, 0, 1], 9114).into(),
+        metadata,
+        metadata_source: crate::config::MetaSource::None,
+        metadata_refresh_interval: Duration::from_secs(60),
+        client_rules,
+        scrape_timeout: Duration::from_secs(1),
+        stream_grace_period: Duration::ZERO,
+        max_clients_per_stream: 0,
+        metric_filter: Default::default(),
+        api_token: None,
+        persist_metadata: false,
+        enable_reload: false,
+    })
+    .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    };
+
+    use prometheus::IntGaugeVec;
+    use warp::{hyper::StatusCode, Filter, Reply};
+
+    use super::{test_context, Context, ScrapeErrorKind, Target};
+    use crate::{
+        config::MetaSource, meta::MetaFile, rules::ClientRules, state::TargetState, xml::RtmpStats,
+    };
+
+    #[test]
+    fn test_parse_target_bare_url() {
+        let target: Target = "http://edge-1:8080/stat".parse().unwrap();
+        assert_eq!(target.name, "edge-1:8080");
+        assert_eq!(target.url.as_str(), "http://edge-1:8080/stat");
+    }
+
+    #[test]
+    fn test_parse_target_named() {
+        let target: Target = "edge=http://edge-1/stat?format=xml".parse().unwrap();
+        assert_eq!(target.name, "edge");
+        assert_eq!(target.url.as_str(), "http://edge-1/stat?format=xml");
+    }
+
+    #[test]
+    fn test_parse_target_invalid() {
+        assert!("edge".parse::<Target>().is_err());
+        assert!("=http://edge-1/stat".parse::<Target>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reload_metadata_from_url() {
+        // serve metadata from a local stand-in for the metadata service
+        let unavailable = Arc::new(AtomicBool::new(true));
+        let route = warp::path!("metadata.json").map({
+            let unavailable = unavailable.clone();
+            move || {
+                if unavailable.load(Ordering::SeqCst) {
+                    StatusCode::SERVICE_UNAVAILABLE.into_response()
+                } else {
+                    r#"{"fields": ["customer"], "metadata": {"my-stream": {"customer": "acme"}}}"#
+                        .into_response()
+                }
+            }
+        });
+        let (addr, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let source = MetaSource::Http(format!("http://{}/metadata.json", addr).parse().unwrap());
+        let mut ctx = test_context(MetaFile::default(), ClientRules::default());
+        let value = |ctx: &Context, name: &str| {
+            let families = ctx.gather();
+            let family = families.iter().find(|family| family.get_name() == name).unwrap();
+            let metric = &family.get_metric()[0];
+            metric.get_gauge().get_value() + metric.get_counter().get_value()
+        };
+        // nothing has been fetched if the first fetch fails
+        let metadata = source.load(&ctx.http).await;
+        assert!(ctx.reload_metadata(metadata).is_err());
+        assert!(ctx.metadata.for_stream("my-stream").is_none());
+        assert_eq!(value(&ctx, "nginx_rtmp_exporter_metadata_reload_success"), 0.0);
+        assert_eq!(value(&ctx, "nginx_rtmp_exporter_metadata_reload_failures_total"), 1.0);
+        assert_eq!(
+            value(&ctx, "nginx_rtmp_exporter_metadata_last_reload_success_timestamp_seconds"),
+            0.0
+        );
+        // fetch the metadata
+        unavailable.store(false, Ordering::SeqCst);
+        let metadata = source.load(&ctx.http).await;
+        ctx.reload_metadata(metadata).unwrap();
+        assert_eq!(ctx.metadata.get_values_for("live", "my-stream").unwrap(), vec!["acme"]);
+        assert_eq!(value(&ctx, "nginx_rtmp_exporter_metadata_reload_success"), 1.0);
+        let fetched =
+            value(&ctx, "nginx_rtmp_exporter_metadata_last_reload_success_timestamp_seconds");
+        assert!(fetched > 0.0);
+        // keep serving the last good copy if the service is unavailable
+        unavailable.store(true, Ordering::SeqCst);
+        let metadata = source.load(&ctx.http).await;
+        assert!(ctx.reload_metadata(metadata).is_err());
+        assert_eq!(ctx.metadata.get_values_for("live", "my-stream").unwrap(), vec!["acme"]);
+        assert_eq!(value(&ctx, "nginx_rtmp_exporter_metadata_reload_success"), 0.0);
+        assert_eq!(value(&ctx, "nginx_rtmp_exporter_metadata_reload_failures_total"), 2.0);
+        assert_eq!(
+            value(&ctx, "nginx_rtmp_exporter_metadata_last_reload_success_timestamp_seconds"),
+            fetched
+        );
+    }
+
+    #[test]
+    fn test_reload_metadata() {
+        let stats: RtmpStats =
+            quick_xml::de::from_str(include_str!("../test/stat_xml.xml")).unwrap();
+        let metadata = |file: &str| toml::from_str::<MetaFile>(file).map_err(Into::into);
+        let initial = r#"
+fields = ["customer"]
+metadata = { "my cool stream" = { customer = "acme" } }
+"#;
+        let mut ctx = test_context(metadata(initial).unwrap(), ClientRules::default());
+        // the labels of the series of the stream, after scraping it
+        let labels = |ctx: &Context| {
+            let mut state = TargetState::default();
+            ctx.record_stats(&ctx.metrics, &mut state, "edge", &stats);
+            let families = ctx.gather();
+            let family = families
+                .iter()
+                .find(|family| family.get_name() == "nginx_rtmp_stream_incoming_bandwidth")
+                .unwrap();
+            let mut labels: Vec<String> = family.get_metric()[0]
+                .get_label()
+                .iter()
+                .map(|label| format!("{}={}", label.get_name(), label.get_value()))
+                .collect();
+            labels.sort();
+            labels
+        };
+        assert!(labels(&ctx).contains(&"customer=acme".to_owned()));
+        // new fields and global fields are added to the series of streams
+        ctx.reload_metadata(metadata(
+            r#"
+fields = ["customer", "region"]
+globalFields = { datacenter = "ams" }
+metadata = { "my cool stream" = { customer = "acme", region = "eu" } }
+"#,
+        ))
+        .unwrap();
+        let reloaded = labels(&ctx);
+        assert!(reloaded.contains(&"region=eu".to_owned()));
+        assert!(reloaded.contains(&"datacenter=ams".to_owned()));
+        // invalid metadata leaves the current metadata exported
+        assert!(ctx.reload_metadata(metadata("fields = [\"stream\"]")).is_err());
+        assert!(ctx.reload_metadata(metadata("fields = [")).is_err());
+        assert_eq!(ctx.metadata.get_fields(), vec!["customer", "region"]);
+        assert_eq!(labels(&ctx), reloaded);
+    }
+
+    #[test]
+    fn test_stream_client_counts() {
+        // add a viewer to the stream, alongside its publishing relay
+        let stats = include_str!("../test/stat_xml.xml").replacen(
+            "</client>",
+            "</client><client><id>1</id><address>198.51.100.1</address><time>0</time>\
+             <flashver>LNX 9,0,124,2</flashver><dropped>0</dropped><avsync>0</avsync>\
+             <timestamp>0</timestamp><active/></client>",
+            1,
+        );
+        let stats: RtmpStats = quick_xml::de::from_str(&stats).unwrap();
+        let counts = |rules: ClientRules| {
+            let ctx = test_context(MetaFile::default(), rules);
+            let mut state = TargetState::default();
+            ctx.record_stats(&ctx.metrics, &mut state, "edge", &stats);
+            let value = |metric: &IntGaugeVec| {
+                metric.with_label_values(&["edge", "0", "test", "my cool stream"]).get()
+            };
+            let metrics = &ctx.metrics;
+            [
+                value(&metrics.nginx_rtmp_stream_total_clients),
+                value(&metrics.nginx_rtmp_stream_publishers),
+                value(&metrics.nginx_rtmp_stream_viewers),
+                value(&metrics.nginx_rtmp_stream_relays),
+            ]
+        };
+        // the publishing relay is not counted as a client
+        assert_eq!(counts(ClientRules::default()), [1, 0, 1, 1]);
+        // rules decide the role of every client, including in the total
+        let rules = toml::from_str(
+            r#"
+[[rules]]
+role = "publisher"
+flashver = "^ngx-local-relay$"
+
+[[rules]]
+role = "remote_relay"
+address = ["198.51.100.0/24"]
+"#,
+        )
+        .unwrap();
+        assert_eq!(counts(rules), [1, 1, 0, 1]);
+    }
+
+    #[test]
+    fn test_scrape_error_kind_parse() {
+        let err = quick_xml::de::from_str::<crate::xml::RtmpStats>("<rtmp/>").unwrap_err();
+        assert_eq!(ScrapeErrorKind::of(&err.into()), ScrapeErrorKind::Parse);
     }
 }
@@ -1,64 +1,362 @@
+mod api;
+mod config;
 mod context;
 mod meta;
 mod metrics;
+mod rules;
+mod state;
 mod xml;
 
 use std::{
     convert::Infallible,
     env,
     error::Error,
-    net::{IpAddr, SocketAddr},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
     path::PathBuf,
     sync::Arc,
+    time::Duration,
 };
 
+use anyhow::{bail, Context as AnyhowContext};
 use clap::Parser;
 use dotenv::dotenv;
 use meta::Format;
-use prometheus::{Encoder, TextEncoder};
-use reqwest::Url;
-use serde::Serialize;
-use tokio::sync::Mutex;
+use prometheus::{proto::MetricFamily, Encoder, TextEncoder};
+use serde::{Deserialize, Serialize};
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::RwLock;
 use tracing::{debug, error, info};
 use tracing_subscriber::fmt::format::FmtSpan;
+use url::Url;
 use warp::{
+    filters::BoxedFilter,
     http::HeaderValue,
     hyper::{header::CONTENT_TYPE, Body, StatusCode},
+    reply::Response,
     Filter, Rejection, Reply,
 };
 
-use crate::{context::Context, meta::MetaFile};
+use crate::{
+    config::{Config, MetaSource, MetricFilter},
+    context::{Context, Scrape, Target},
+    meta::MetaFile,
+    rules::ClientRules,
+};
 
 /// Prometheus data exporter for NGINX servers running the nginx-rtmp-module.
+///
+/// Every option can also be set by its environment variable, or in the
+/// configuration file. Command line flags take precedence over environment
+/// variables, which take precedence over the configuration file.
 #[derive(Parser)]
 struct Args {
-    /// The RTMP statistics endpoint of NGINX.
-    #[clap(long)]
-    pub scrape_url: Url,
-    /// The host to listen on.
-    #[clap(default_value = "127.0.0.1", long)]
-    pub host: IpAddr,
-    /// The port to listen on.
-    #[clap(default_value = "9114", short, long)]
-    pub port: u16,
-    /// An optional path to a metadata file.
-    #[clap(long)]
+    /// An optional path to a TOML or YAML configuration file.
+    #[clap(long, env = "NGINX_RTMP_EXPORTER_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// An RTMP statistics endpoint of NGINX to scrape, given as either `<URL>`
+    /// or `<NAME>=<URL>`. May be specified multiple times, or as a list
+    /// separated by commas in `NGINX_RTMP_EXPORTER_TARGETS`.
+    #[clap(long = "target", visible_alias = "scrape-url")]
+    pub targets: Vec<Target>,
+    /// The host to listen on [default: 127.0.0.1].
+    #[clap(long, env = "NGINX_RTMP_EXPORTER_HOST")]
+    pub host: Option<IpAddr>,
+    /// The port to listen on [default: 9114].
+    #[clap(short, long, env = "NGINX_RTMP_EXPORTER_PORT")]
+    pub port: Option<u16>,
+    /// An optional path to a metadata file, used instead of the metadata in the
+    /// configuration file.
+    #[clap(long, env = "NGINX_RTMP_EXPORTER_METADATA")]
     pub metadata: Option<PathBuf>,
-    /// An optional format for the metadata file.
-    #[clap(long, default_value = "json")]
-    pub format: Format,
+    /// Read the metadata from `RTMP_META_*` environment variables, instead of
+    /// the metadata in the configuration file.
+    #[clap(long, conflicts_with = "metadata", env = "NGINX_RTMP_EXPORTER_METADATA_FROM_ENV")]
+    pub metadata_from_env: bool,
+    /// An optional URL to periodically fetch the metadata from, instead of the
+    /// metadata in the configuration file.
+    #[clap(
+        long,
+        conflicts_with_all = ["metadata", "metadata_from_env"],
+        env = "NGINX_RTMP_EXPORTER_METADATA_URL"
+    )]
+    pub metadata_url: Option<Url>,
+    /// How long, in seconds, to wait between fetches of the metadata URL
+    /// [default: 60].
+    #[clap(long, env = "NGINX_RTMP_EXPORTER_METADATA_REFRESH_INTERVAL")]
+    pub metadata_refresh_interval: Option<u64>,
+    /// An optional format for the metadata and client rules files, one of
+    /// json, toml or yaml [default: detected from the file extension, or json].
+    #[clap(long, env = "NGINX_RTMP_EXPORTER_FORMAT")]
+    pub format: Option<Format>,
+    /// An optional path to a file of rules deciding the role of each client,
+    /// used instead of the client rules in the configuration file.
+    #[clap(long, env = "NGINX_RTMP_EXPORTER_CLIENT_RULES")]
+    pub client_rules: Option<PathBuf>,
+    /// How long, in seconds, to wait for a target to respond [default: 3].
+    #[clap(long, env = "NGINX_RTMP_EXPORTER_SCRAPE_TIMEOUT")]
+    pub scrape_timeout: Option<u64>,
+    /// How long, in seconds, a stream may be missing from the statistics
+    /// before its series are removed [default: 0].
+    #[clap(long, env = "NGINX_RTMP_EXPORTER_STREAM_GRACE_PERIOD")]
+    pub stream_grace_period: Option<u64>,
+    /// The maximum number of clients per stream to export per-client metrics
+    /// for. Per-client metrics are disabled by default [default: 0].
+    #[clap(long, env = "NGINX_RTMP_EXPORTER_MAX_CLIENTS_PER_STREAM")]
+    pub max_clients_per_stream: Option<usize>,
+    /// An optional bearer token enabling the metadata API. Prefer setting this
+    /// by its environment variable, to keep it out of the process list.
+    #[clap(long, env = "NGINX_RTMP_EXPORTER_API_TOKEN", hide_env_values = true)]
+    pub api_token: Option<String>,
+    /// Write changes made through the metadata API back to the metadata file.
+    #[clap(long, env = "NGINX_RTMP_EXPORTER_PERSIST_METADATA")]
+    pub persist_metadata: bool,
+    /// Enable reloading the metadata with a `POST /-/reload` request, which
+    /// requires the API token if one is set.
+    #[clap(long, env = "NGINX_RTMP_EXPORTER_ENABLE_RELOAD")]
+    pub enable_reload: bool,
+}
+
+/// The settings of the exporter, resolved from the command line, environment
+/// variables and configuration file.
+struct Settings {
+    targets: Vec<Target>,
+    addr: SocketAddr,
+    metadata: MetaFile,
+    metadata_source: MetaSource,
+    metadata_refresh_interval: Duration,
+    client_rules: ClientRules,
+    scrape_timeout: Duration,
+    stream_grace_period: Duration,
+    max_clients_per_stream: usize,
+    metric_filter: MetricFilter,
+    api_token: Option<String>,
+    persist_metadata: bool,
+    enable_reload: bool,
+}
+
+impl Settings {
+    /// Resolve the settings of the exporter, loading the configuration file if
+    /// one is given.
+    fn resolve(args: Args) -> anyhow::Result<Self> {
+        let config = match &args.config {
+            Some(path) => {
+                let config = Config::from_path(path)?;
+                info!("Loaded config from {:?}", path);
+                config
+            }
+            None => Config::default(),
+        };
+        let targets = if !args.targets.is_empty() {
+            args.targets
+        } else {
+            let targets = targets_from_env()?;
+            if targets.is_empty() {
+                config.targets
+            } else {
+                targets
+            }
+        };
+        if targets.is_empty() {
+            bail!("No targets to scrape, pass --target or set targets in the config file");
+        }
+        let host = args.host.or(config.host).unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST));
+        let port = args.port.or(config.port).unwrap_or(9114);
+        // load metadata, remembering where it came from so it can be reloaded
+        let (metadata, metadata_source) = match args.metadata {
+            Some(path) => {
+                let format = args.format.unwrap_or_else(|| Format::detect(&path));
+                let metadata = MetaFile::from_path(&path, format)?;
+                info!("Loaded metadata from {:?}", path);
+                (metadata, MetaSource::File(path, format))
+            }
+            None if args.metadata_from_env => {
+                let metadata = MetaFile::from_env()?;
+                info!("Loaded metadata for {} streams from environment", metadata.metadata.len());
+                (metadata, MetaSource::Env)
+            }
+            // metadata is fetched once the exporter has started
+            None => match args.metadata_url.or(config.metadata_url) {
+                Some(url) => (MetaFile::default(), MetaSource::Http(url)),
+                None => match &args.config {
+                    Some(path) => {
+                        (config.metadata.unwrap_or_default(), MetaSource::Config(path.clone()))
+                    }
+                    None => (MetaFile::default(), MetaSource::None),
+                },
+            },
+        };
+        let persist_metadata = args.persist_metadata || config.persist_metadata.unwrap_or(false);
+        if persist_metadata && !matches!(metadata_source, MetaSource::File(..)) {
+            bail!("Persisting metadata requires a metadata file, pass --metadata");
+        }
+        let metadata_refresh_interval = Duration::from_secs(
+            args.metadata_refresh_interval.or(config.metadata_refresh_interval).unwrap_or(60),
+        );
+        if metadata_refresh_interval.is_zero() {
+            bail!("The metadata refresh interval must be at least one second");
+        }
+        // load client rules
+        let client_rules = match args.client_rules {
+            Some(path) => {
+                let format = args.format.unwrap_or_else(|| Format::detect(&path));
+                let rules = ClientRules::from_path(&path, format)?;
+                info!("Loaded {} client rules from {:?}", rules.rules.len(), path);
+                rules
+            }
+            None => ClientRules { rules: config.client_rules },
+        };
+        Ok(Self {
+            targets,
+            addr: SocketAddr::from((host, port)),
+            metadata,
+            metadata_source,
+            metadata_refresh_interval,
+            client_rules,
+            scrape_timeout: Duration::from_secs(
+                args.scrape_timeout.or(config.scrape_timeout).unwrap_or(3),
+            ),
+            stream_grace_period: Duration::from_secs(
+                args.stream_grace_period.or(config.stream_grace_period).unwrap_or(0),
+            ),
+            max_clients_per_stream: args
+                .max_clients_per_stream
+                .or(config.max_clients_per_stream)
+                .unwrap_or(0),
+            metric_filter: config.metrics,
+            api_token: args.api_token.or(config.api_token),
+            persist_metadata,
+            enable_reload: args.enable_reload || config.enable_reload.unwrap_or(false),
+        })
+    }
+}
+
+/// Read the targets listed in `NGINX_RTMP_EXPORTER_TARGETS`, separated by
+/// commas. Targets given on the command line are not split, so that their URLs
+/// may contain commas.
+fn targets_from_env() -> anyhow::Result<Vec<Target>> {
+    let targets = match env::var("NGINX_RTMP_EXPORTER_TARGETS") {
+        Ok(targets) => targets,
+        Err(_) => return Ok(Vec::new()),
+    };
+    targets
+        .split(',')
+        .map(str::trim)
+        .filter(|target| !target.is_empty())
+        .map(|target| target.parse().context("Invalid target in NGINX_RTMP_EXPORTER_TARGETS"))
+        .collect()
 }
 
-fn encode_metrics() -> Result<(TextEncoder, String), Box<dyn Error>> {
+/// Query parameters of the `/probe` endpoint.
+#[derive(Deserialize)]
+struct ProbeQuery {
+    /// The name of a configured target, or a target URL to scrape.
+    target: String,
+}
+
+fn encode_metrics(
+    metric_families: Vec<MetricFamily>,
+    filter: &MetricFilter,
+) -> Result<(TextEncoder, String), Box<dyn Error>> {
     let encoder = TextEncoder::new();
     let mut buf = String::new();
-    // gather and encode metrics
-    let metric_families = prometheus::gather();
+    // filter and encode metrics
+    let metric_families = filter.apply(metric_families);
     encoder.encode_utf8(&metric_families, &mut buf)?;
     // return encoder and buffer
     Ok((encoder, buf))
 }
 
+/// Build the response to a metrics request.
+fn metrics_response(res: Result<(TextEncoder, String), Box<dyn Error>>) -> Response {
+    match res {
+        Ok((encoder, buf)) => {
+            let mut res = Response::new(Body::from(buf));
+            res.headers_mut()
+                .insert(CONTENT_TYPE, HeaderValue::from_str(encoder.format_type()).unwrap());
+            res
+        }
+        Err(err) => {
+            error!("Failed to collect metrics");
+            error!("{}", err);
+            warp::reply::with_status(warp::reply(), StatusCode::INTERNAL_SERVER_ERROR)
+                .into_response()
+        }
+    }
+}
+
+/// Scrape a single target into a per-request registry.
+async fn probe(
+    query: ProbeQuery,
+    ctx: Arc<RwLock<Context>>,
+    filter: Arc<MetricFilter>,
+) -> Response {
+    let (http, target) = {
+        let ctx = ctx.read().await;
+        match ctx.resolve_probe_target(&query.target) {
+            Ok(target) => (ctx.http.clone(), target),
+            Err(err) => {
+                debug!("invalid probe target: {}", err);
+                return error_response(StatusCode::BAD_REQUEST, "INVALID_TARGET");
+            }
+        }
+    };
+    // fetch without holding the context lock, so a slow target does not
+    // block metadata reloads and updates
+    let scrape = Scrape::fetch(&http, target).await;
+    metrics_response(match ctx.read().await.probe(scrape) {
+        Ok(registry) => encode_metrics(registry.gather(), &filter),
+        Err(err) => Err(err.into()),
+    })
+}
+
+/// Reload the metadata from its source, replacing the metadata of the context
+/// if the new metadata is valid.
+async fn reload_metadata(ctx: &RwLock<Context>, source: &MetaSource) -> anyhow::Result<()> {
+    let http = ctx.read().await.http.clone();
+    let metadata = source.load(&http).await;
+    let res = ctx.write().await.reload_metadata(metadata);
+    match &res {
+        Ok(()) => info!("Reloaded metadata from {}", source),
+        Err(err) => error!("Failed to reload metadata: {:#}", err),
+    }
+    res
+}
+
+/// Reload the metadata in response to a `POST /-/reload` request.
+async fn reload(ctx: Arc<RwLock<Context>>, source: Arc<MetaSource>) -> Response {
+    match reload_metadata(&ctx, &source).await {
+        Ok(()) => warp::reply().into_response(),
+        Err(_) => error_response(StatusCode::INTERNAL_SERVER_ERROR, "RELOAD_FAILED"),
+    }
+}
+
+/// Create the `POST /-/reload` route, which is only found if reloading is
+/// enabled, and requires the API token if one is given.
+fn reload_route(
+    ctx: Arc<RwLock<Context>>,
+    enabled: bool,
+    token: Option<String>,
+    source: Arc<MetaSource>,
+) -> BoxedFilter<(Response,)> {
+    warp::path!("-" / "reload")
+        .and(warp::path::end())
+        .and(warp::any().and_then(move || async move {
+            if enabled {
+                Ok(())
+            } else {
+                Err(warp::reject::not_found())
+            }
+        }))
+        .untuple_one()
+        .and(api::require_token(token))
+        .and(warp::post())
+        .and(warp::any().map(move || ctx.clone()))
+        .and(warp::any().map(move || source.clone()))
+        .then(reload)
+        .boxed()
+}
+
 /// An API error serializable to JSON.
 #[derive(Serialize)]
 struct ErrorMessage {
@@ -66,6 +364,12 @@ struct ErrorMessage {
     message: String,
 }
 
+/// Build an error response with the given status code and message.
+fn error_response(code: StatusCode, message: &str) -> Response {
+    let json = warp::reply::json(&ErrorMessage { code: code.as_u16(), message: message.into() });
+    warp::reply::with_status(json, code).into_response()
+}
+
 async fn handle_rejection(err: Rejection) -> Result<impl Reply, Infallible> {
     let code;
     let message;
@@ -75,16 +379,16 @@ async fn handle_rejection(err: Rejection) -> Result<impl Reply, Infallible> {
         message = "NOT_FOUND";
     } else if let Some(e) = err.find::<warp::filters::body::BodyDeserializeError>() {
         message = match e.source() {
-            Some(cause) => {
-                if cause.to_string().contains("denom") {
-                    "FIELD_ERROR: denom"
-                } else {
-                    "BAD_REQUEST"
-                }
-            }
-            None => "BAD_REQUEST",
+            Some(cause) if cause.to_string().contains("denom") => "FIELD_ERROR: denom",
+            _ => "BAD_REQUEST",
         };
         code = StatusCode::BAD_REQUEST;
+    } else if err.find::<api::Unauthorized>().is_some() {
+        code = StatusCode::UNAUTHORIZED;
+        message = "UNAUTHORIZED";
+    } else if err.find::<warp::reject::InvalidQuery>().is_some() {
+        code = StatusCode::BAD_REQUEST;
+        message = "INVALID_QUERY";
     } else if err.find::<warp::reject::MethodNotAllowed>().is_some() {
         code = StatusCode::METHOD_NOT_ALLOWED;
         message = "METHOD_NOT_ALLOWED";
@@ -100,7 +404,11 @@ async fn handle_rejection(err: Rejection) -> Result<impl Reply, Infallible> {
 }
 
 #[tokio::main]
-async fn main() {
+async fn main() -> anyhow::Result<()> {
+    // load dotenv if in dev env, before reading environment variables
+    if cfg!(debug_assertions) {
+        dotenv().ok();
+    }
     let args: Args = Args::parse();
     // intialize tracing
     let filter =
@@ -114,54 +422,239 @@ async fn main() {
         rustc_version = env!("VERGEN_RUSTC_SEMVER"),
         builder_host = env!("VERGEN_RUSTC_HOST_TRIPLE")
     );
-    // load dotenv if in dev env
-    if cfg!(debug_assertions) {
-        dotenv().ok();
-    }
-    // load metadata
-    let provider = match args.metadata {
-        Some(path) => {
-            let provider =
-                MetaFile::from_path(&path, args.format).expect("Failed to load metadata");
-            info!("Loaded metadata from {:?}", path);
-            provider
-        }
-        None => MetaFile::default(),
-    };
+    // resolve settings
+    let settings = Settings::resolve(args).context("Failed to load configuration")?;
     // create threadsafe context
-    let ctx = Context::new(args.scrape_url, provider).unwrap();
-    let ctx = Arc::new(Mutex::new(ctx));
+    let ctx = Context::new(&settings).context("Failed to create context")?;
+    // metadata from a URL is not loaded until it is first fetched
+    if !matches!(settings.metadata_source, MetaSource::Http(_)) {
+        ctx.metadata_status.record(true);
+    }
+    let ctx = Arc::new(RwLock::new(ctx));
+    // reload metadata on SIGHUP
+    let metadata_source = Arc::new(settings.metadata_source);
+    #[cfg(unix)]
+    {
+        let ctx = ctx.clone();
+        let source = metadata_source.clone();
+        let mut hangup = signal(SignalKind::hangup()).context("Failed to listen for SIGHUP")?;
+        tokio::spawn(async move {
+            while hangup.recv().await.is_some() {
+                reload_metadata(&ctx, &source).await.ok();
+            }
+        });
+    }
+    // periodically fetch metadata from a URL
+    if let MetaSource::Http(url) = metadata_source.as_ref() {
+        info!("Fetching metadata from {} every {:?}", url, settings.metadata_refresh_interval);
+        let ctx = ctx.clone();
+        let source = metadata_source.clone();
+        let mut refresh = tokio::time::interval(settings.metadata_refresh_interval);
+        tokio::spawn(async move {
+            loop {
+                refresh.tick().await;
+                // the previous metadata is kept if the fetch fails
+                reload_metadata(&ctx, &source).await.ok();
+            }
+        });
+    }
+    // create metadata api filter
+    let api = api::routes(
+        ctx.clone(),
+        settings.api_token.clone(),
+        settings.persist_metadata.then(|| metadata_source.clone()),
+    );
+    // create reload filter
+    let reload =
+        reload_route(ctx.clone(), settings.enable_reload, settings.api_token, metadata_source);
     // create context filter
     let ctx = warp::any().map(move || ctx.clone());
+    // create metric filter filter
+    let metric_filter = Arc::new(settings.metric_filter);
+    let metric_filter = warp::any().map(move || metric_filter.clone());
     // create index filter
     let index = warp::get()
         .and(warp::path!("metrics"))
         .and(warp::path::end())
-        .and(ctx)
-        .then(|ctx: Arc<Mutex<Context>>| async move {
-            let mut ctx = ctx.lock().await;
-            ctx.collect_metrics().await;
-            encode_metrics()
-        })
-        .map(|res: Result<(TextEncoder, String), Box<dyn Error>>| match res {
-            Ok((encoder, buf)) => {
-                let mut res = warp::reply::Response::new(Body::from(buf));
-                res.headers_mut()
-                    .insert(CONTENT_TYPE, HeaderValue::from_str(encoder.format_type()).unwrap());
-                res
-            }
-            Err(err) => {
-                error!("Failed to collect metrics");
-                error!("{}", err);
-                warp::reply::with_status(warp::reply(), StatusCode::INTERNAL_SERVER_ERROR)
-                    .into_response()
-            }
+        .and(ctx.clone())
+        .and(metric_filter.clone())
+        .then(|ctx: Arc<RwLock<Context>>, filter: Arc<MetricFilter>| async move {
+            let (http, targets) = {
+                let ctx = ctx.read().await;
+                (ctx.http.clone(), ctx.targets.clone())
+            };
+            let scrapes = Scrape::fetch_all(&http, &targets).await;
+            let ctx = ctx.read().await;
+            ctx.collect_metrics(scrapes);
+            encode_metrics(ctx.gather(), &filter)
         })
+        .map(metrics_response);
+    // create probe filter
+    let probe = warp::get()
+        .and(warp::path!("probe"))
+        .and(warp::path::end())
+        .and(warp::query::<ProbeQuery>())
+        .and(ctx.clone())
+        .and(metric_filter)
+        .then(probe);
+    // combine routes
+    let routes = index
+        .or(probe)
+        .or(reload)
+        .or(api)
         .recover(handle_rejection)
         .with(warp::trace::request())
         .with(warp::log("nginx_rtmp_exporter"));
-    // get address and listen
-    let addr = SocketAddr::from((args.host, args.port));
-    info!("Listening for requests on {}", addr);
-    warp::serve(index).try_bind(addr).await;
+    // listen
+    info!("Listening for requests on {}", settings.addr);
+    warp::serve(routes).try_bind(settings.addr).await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env, fs,
+        sync::{Arc, Mutex, MutexGuard},
+    };
+
+    use clap::Parser;
+    use tokio::sync::RwLock;
+    use warp::{hyper::StatusCode, Filter};
+
+    use super::{handle_rejection, reload_route, Args, Settings};
+    use crate::{
+        config::MetaSource,
+        context::test_context,
+        meta::{Format, MetaFile},
+        rules::ClientRules,
+    };
+
+    /// Held by tests parsing arguments, as arguments are also read from the
+    /// environment of the whole process.
+    static ENV: Mutex<()> = Mutex::new(());
+
+    fn lock_env() -> MutexGuard<'static, ()> {
+        ENV.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    #[test]
+    fn test_settings_precedence() {
+        let path = env::temp_dir()
+            .join(format!("nginx-rtmp-exporter-test-config-{}.toml", std::process::id()));
+        fs::write(
+            &path,
+            r#"
+targets = ["file=http://ingest-1/stat"]
+host = "0.0.0.0"
+port = 9200
+scrape_timeout = 5
+stream_grace_period = 30
+"#,
+        )
+        .unwrap();
+        let config = path.to_str().unwrap();
+        let env = lock_env();
+        env::set_var("NGINX_RTMP_EXPORTER_STREAM_GRACE_PERIOD", "60");
+        env::set_var("NGINX_RTMP_EXPORTER_SCRAPE_TIMEOUT", "10");
+        let args = Args::try_parse_from([
+            "nginx-rtmp-exporter",
+            "--config",
+            config,
+            "--target",
+            "cli=http://edge-1/stat",
+            "--scrape-timeout",
+            "1",
+        ])
+        .unwrap();
+        env::remove_var("NGINX_RTMP_EXPORTER_STREAM_GRACE_PERIOD");
+        env::remove_var("NGINX_RTMP_EXPORTER_SCRAPE_TIMEOUT");
+        drop(env);
+        let settings = Settings::resolve(args).unwrap();
+        fs::remove_file(&path).ok();
+        // the command line takes precedence over the environment
+        assert_eq!(settings.scrape_timeout.as_secs(), 1);
+        assert_eq!(settings.targets.len(), 1);
+        assert_eq!(settings.targets[0].name, "cli");
+        // the environment takes precedence over the config file
+        assert_eq!(settings.stream_grace_period.as_secs(), 60);
+        // the config file takes precedence over the defaults
+        assert_eq!(settings.addr.to_string(), "0.0.0.0:9200");
+        assert_eq!(settings.max_clients_per_stream, 0);
+    }
+
+    #[test]
+    fn test_settings_targets() {
+        let env = lock_env();
+        env::set_var(
+            "NGINX_RTMP_EXPORTER_TARGETS",
+            "edge-1=http://edge-1/stat, http://edge-2/stat",
+        );
+        // targets in the environment are separated by commas
+        let args = Args::try_parse_from(["nginx-rtmp-exporter"]).unwrap();
+        let targets = Settings::resolve(args).map(|settings| settings.targets);
+        // targets on the command line are not split, and take precedence
+        let args = Args::try_parse_from([
+            "nginx-rtmp-exporter",
+            "--target",
+            "http://edge-3/stat?app=a,b",
+            "--target",
+            "edge-4=http://edge-4/stat",
+        ])
+        .unwrap();
+        let cli_targets = Settings::resolve(args).map(|settings| settings.targets);
+        env::remove_var("NGINX_RTMP_EXPORTER_TARGETS");
+        drop(env);
+        let names: Vec<String> = targets.unwrap().into_iter().map(|target| target.name).collect();
+        assert_eq!(names, vec!["edge-1", "edge-2"]);
+        let cli_targets = cli_targets.unwrap();
+        assert_eq!(cli_targets.len(), 2);
+        assert_eq!(cli_targets[0].url.as_str(), "http://edge-3/stat?app=a,b");
+        assert_eq!(cli_targets[1].name, "edge-4");
+    }
+
+    #[test]
+    fn test_settings_no_targets() {
+        let _env = lock_env();
+        let args = Args::try_parse_from(["nginx-rtmp-exporter"]).unwrap();
+        assert!(Settings::resolve(args).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reload_route() {
+        let path = env::temp_dir()
+            .join(format!("nginx-rtmp-exporter-test-reload-{}.json", std::process::id()));
+        fs::write(
+            &path,
+            r#"{"fields": ["customer"], "metadata": {"my-stream": {"customer": "acme"}}}"#,
+        )
+        .unwrap();
+        let ctx = test_context(MetaFile::default(), ClientRules::default());
+        let ctx = Arc::new(RwLock::new(ctx));
+        let source = Arc::new(MetaSource::File(path.clone(), Format::Json));
+        let reload = |enabled: bool, token: Option<&str>| {
+            reload_route(ctx.clone(), enabled, token.map(str::to_owned), source.clone())
+                .recover(handle_rejection)
+        };
+        let request = || warp::test::request().method("POST").path("/-/reload");
+        // reloading is not found unless enabled
+        let res = request().reply(&reload(false, None)).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        // the token is required if one is set
+        let res = request().reply(&reload(true, Some("secret"))).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        assert!(ctx.read().await.metadata.for_stream("my-stream").is_none());
+        let res = request()
+            .header("authorization", "Bearer secret")
+            .reply(&reload(true, Some("secret")))
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(ctx.read().await.metadata.for_stream("my-stream").is_some());
+        // invalid metadata is not loaded
+        fs::write(&path, r#"{"fields": ["stream"]}"#).unwrap();
+        let res = request().reply(&reload(true, None)).await;
+        fs::remove_file(&path).ok();
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(ctx.read().await.metadata.get_fields(), vec!["customer"]);
+    }
 }
@@ -1,19 +1,29 @@
 //! Handles supplying custom metadata to the metrics scraper.
-use std::{collections::HashMap, fmt::Debug, fs, path::Path, str::FromStr};
+use std::{collections::HashMap, env, fmt::Debug, fs, path::Path, str::FromStr};
 
 use anyhow::{bail, Context, Result};
-use serde::Deserialize;
+use regex::{Captures, Regex};
+use serde::{Deserialize, Serialize};
 
-#[derive(Default, Debug, Deserialize)]
+use crate::metrics::{BUILTIN_LABELS, EXPORTER_LABELS};
+
+#[derive(Default, Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct MetaFile {
     /// Global data.
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub global_fields: Option<HashMap<String, String>>,
     /// A list of fields to specify for each stream.
-    pub fields: Vec<String>,
+    pub fields: Vec<MetaField>,
     /// The metadata to apply. This is a hashmap of stream names to a map of
     /// metadata entries.
+    #[serde(default)]
     pub metadata: HashMap<String, HashMap<String, String>>,
+    /// Metadata to apply to every stream matching a pattern. Values given
+    /// for a stream in `metadata` take precedence over these, and earlier
+    /// patterns take precedence over later ones.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub patterns: Vec<MetaPattern>,
 }
 
 impl MetaFile {
@@ -22,6 +32,7 @@ impl MetaFile {
         match format {
             Format::Json => Self::from_json(path),
             Format::Toml => Self::from_toml(path),
+            Format::Yaml => Self::from_yaml(path),
         }
     }
 
@@ -37,6 +48,125 @@ impl MetaFile {
         serde_json::from_str(&file).context("Failed to parse meta file")
     }
 
+    /// Create a metadata provider from a YAML file.
+    pub fn from_yaml<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let file = fs::read_to_string(path).context("Failed to read meta file")?;
+        serde_yaml::from_str(&file).context("Failed to parse meta file")
+    }
+
+    /// Create a metadata provider from the contents of a file, specifying the
+    /// file format.
+    pub fn parse(file: &str, format: Format) -> Result<Self> {
+        match format {
+            Format::Json => serde_json::from_str(file).context("Failed to parse meta file"),
+            Format::Toml => toml::from_str(file).context("Failed to parse meta file"),
+            Format::Yaml => serde_yaml::from_str(file).context("Failed to parse meta file"),
+        }
+    }
+
+    /// Create a metadata provider from the environment variables of the
+    /// exporter. See [`MetaFile::from_vars`].
+    pub fn from_env() -> Result<Self> {
+        Self::from_vars(
+            env::vars_os().filter_map(|(key, value)| {
+                Some((key.into_string().ok()?, value.into_string().ok()?))
+            }),
+        )
+    }
+
+    /// Create a metadata provider from environment variables. The fields are
+    /// listed, separated by commas, in `RTMP_META_FIELDS`, and the value of a
+    /// field for a stream is given by `RTMP_META_<stream>_<field>`. Other
+    /// variables are ignored.
+    pub fn from_vars<I: IntoIterator<Item = (String, String)>>(vars: I) -> Result<Self> {
+        let vars: Vec<(String, String)> = vars
+            .into_iter()
+            .filter_map(|(key, value)| Some((key.strip_prefix(ENV_PREFIX)?.to_owned(), value)))
+            .collect();
+        let mut file = MetaFile::default();
+        if let Some((_, fields)) = vars.iter().find(|(key, _)| key == "FIELDS") {
+            fields
+                .split(',')
+                .map(str::trim)
+                .filter(|field| !field.is_empty())
+                .for_each(|field| file.add_field(field));
+        }
+        for (key, value) in vars.iter().filter(|(key, _)| key != "FIELDS") {
+            // stream names may contain underscores, so match the longest field
+            // name ending the variable
+            let (stream, field) = file
+                .get_fields()
+                .into_iter()
+                .filter_map(|field| {
+                    let stream = key.strip_suffix(field)?.strip_suffix('_')?;
+                    (!stream.is_empty()).then_some((stream, field))
+                })
+                .max_by_key(|(_, field)| field.len())
+                .map(|(stream, field)| (stream.to_owned(), field.to_owned()))
+                .with_context(|| format!("Unknown meta field in {}{}", ENV_PREFIX, key))?;
+            file = file.with_value(stream, field, value.clone())?;
+        }
+        Ok(file)
+    }
+
+    /// Write this metadata to a file, specifying the file format. The file is
+    /// replaced atomically, so that it is never left partially written.
+    pub fn save(&self, path: impl AsRef<Path>, format: Format) -> Result<()> {
+        let path = path.as_ref();
+        let file = match format {
+            Format::Json => serde_json::to_string_pretty(self)?,
+            Format::Toml => toml::to_string(self)?,
+            Format::Yaml => serde_yaml::to_string(self)?,
+        };
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        fs::write(&tmp, file).context("Failed to write meta file")?;
+        fs::rename(&tmp, path).context("Failed to replace meta file")
+    }
+
+    /// Check that this metadata can be exported, returning the first problem
+    /// found otherwise.
+    pub fn validate(&self) -> Result<()> {
+        for (i, field) in self.fields.iter().enumerate() {
+            validate_label_name(&field.name)?;
+            if BUILTIN_LABELS.contains(&field.name.as_str()) {
+                bail!("Meta field is reserved by the exporter: {}", field.name);
+            }
+            if self.fields[..i].iter().any(|other| other.name == field.name) {
+                bail!("Duplicate meta field: {}", field.name);
+            }
+            if field.missing == Missing::Inherit && self.global_value(&field.name).is_none() {
+                bail!("Meta field {} inherits from a missing global field", field.name);
+            }
+        }
+        for field in self.global_fields.iter().flat_map(|fields| fields.keys()) {
+            validate_label_name(field)?;
+            if BUILTIN_LABELS.contains(&field.as_str()) || EXPORTER_LABELS.contains(&field.as_str())
+            {
+                bail!("Global field is reserved by the exporter: {}", field);
+            }
+            // only a field inheriting from a global field may share its label
+            if self
+                .fields
+                .iter()
+                .any(|other| &other.name == field && other.missing != Missing::Inherit)
+            {
+                bail!("Global field {} collides with a meta field of the same name", field);
+            }
+        }
+        for (stream, field, _) in self.entries() {
+            if !self.has_field(&field) {
+                bail!("Unknown meta field {} for stream {}", field, stream);
+            }
+        }
+        for pattern in &self.patterns {
+            if let Some(field) = pattern.def.values.keys().find(|field| !self.has_field(field)) {
+                bail!("Unknown meta field {} for pattern {}", field, pattern.regex);
+            }
+        }
+        Ok(())
+    }
+
     /// Add a value to this provider.
     pub fn with_value<S: AsRef<str>>(mut self, stream: S, field: S, value: S) -> Result<Self> {
         self.add_value(stream, field, value)?;
@@ -46,7 +176,7 @@ impl MetaFile {
     /// Borrow and add a value to this provider.
     pub fn add_value<S: AsRef<str>>(&mut self, stream: S, field: S, value: S) -> Result<()> {
         // check if an illegal field is specified
-        if !self.fields.contains(&field.as_ref().to_owned()) {
+        if !self.has_field(field.as_ref()) {
             bail!("Unknown meta field: {}", field.as_ref());
         }
         // check if the stream is already in the metadata
@@ -71,27 +201,90 @@ impl MetaFile {
         Ok(())
     }
 
-    /// Get a reference to the metadata.
-    pub fn get_fields(&self) -> &Vec<String> {
-        &self.fields
+    /// Replace the metadata of a stream, returning its previous metadata. The
+    /// metadata is left unchanged if any of the values are for an unknown field.
+    pub fn set_stream<S: AsRef<str>>(
+        &mut self,
+        stream: S,
+        values: HashMap<String, String>,
+    ) -> Result<Option<HashMap<String, String>>> {
+        if let Some(field) = values.keys().find(|field| !self.has_field(field)) {
+            bail!("Unknown meta field: {}", field);
+        }
+        let stream = stream.as_ref();
+        let previous = self.metadata.insert(stream.to_owned(), HashMap::default());
+        self.add_value_many(stream, values.iter().map(|(field, value)| (&**field, &**value)))?;
+        Ok(previous)
+    }
+
+    /// Remove the metadata of a stream, returning it if the stream had any.
+    pub fn remove_stream<S: AsRef<str>>(&mut self, stream: S) -> Option<HashMap<String, String>> {
+        self.metadata.remove(stream.as_ref())
+    }
+
+    /// Get the names of the metadata fields.
+    pub fn get_fields(&self) -> Vec<&str> {
+        self.fields.iter().map(|field| field.name.as_str()).collect()
+    }
+
+    /// This method checks if the provider has a field with the given name.
+    pub fn has_field(&self, name: &str) -> bool {
+        self.fields.iter().any(|field| field.name == name)
     }
 
     /// Add a field to the provider.
     fn add_field<S: AsRef<str>>(&mut self, field: S) {
-        self.fields.push(field.as_ref().to_owned());
+        self.fields.push(MetaField::new(field.as_ref()));
     }
 
-    /// Get the field-sorted values of a stream's meta.
-    pub fn get_values_for<S: AsRef<str> + Debug>(&self, stream: S) -> Vec<String> {
+    /// Get the names of the fields inheriting from a global field.
+    pub fn inherited_fields(&self) -> impl Iterator<Item = &str> {
+        self.fields
+            .iter()
+            .filter(|field| field.missing == Missing::Inherit)
+            .map(|field| field.name.as_str())
+    }
+
+    /// Get the value of a global field.
+    fn global_value(&self, name: &str) -> Option<&String> {
+        self.global_fields.as_ref().and_then(|fields| fields.get(name))
+    }
+
+    /// Get the field-sorted values of a stream's meta. Each field takes its
+    /// value from the entry for the stream scoped by its application
+    /// (`application/stream`) if it has one, then from the entry for the
+    /// stream name alone, then from the first matching pattern giving a value
+    /// for it, and otherwise from its missing value policy. Returns `None` if
+    /// the stream should not be exported.
+    pub fn get_values_for<S: AsRef<str> + Debug>(
+        &self,
+        application: S,
+        stream: S,
+    ) -> Option<Vec<String>> {
+        let scoped = self.metadata.get(&format!("{}/{}", application.as_ref(), stream.as_ref()));
+        let exact = self.metadata.get(stream.as_ref());
+        let matches: Vec<(&MetaPattern, Captures)> = self
+            .patterns
+            .iter()
+            .filter_map(|pattern| pattern.regex.captures(stream.as_ref()).map(|c| (pattern, c)))
+            .collect();
         self.fields
             .iter()
             .map(|field| {
-                self.metadata
-                    .get(stream.as_ref())
-                    .unwrap_or(&HashMap::new())
-                    .get(field)
-                    .unwrap_or(&"unspecified".to_owned())
-                    .to_owned()
+                let name = &field.name;
+                scoped
+                    .and_then(|values| values.get(name).cloned())
+                    .or_else(|| exact.and_then(|values| values.get(name).cloned()))
+                    .or_else(|| {
+                        matches.iter().find_map(|(pattern, captures)| pattern.value(name, captures))
+                    })
+                    .or_else(|| match &field.missing {
+                        Missing::Default(value) => Some(value.clone()),
+                        Missing::Drop => None,
+                        Missing::Inherit => {
+                            Some(self.global_value(name).cloned().unwrap_or_default())
+                        }
+                    })
             })
             .collect()
     }
@@ -117,13 +310,198 @@ impl MetaFile {
     }
 }
 
+/// A field given for each stream, and what to do for streams with no value for
+/// it. A field is either written as its name, or as a table such as
+/// `{ name = "customer", default = "none" }` or
+/// `{ name = "region", missing = "inherit" }`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "MetaFieldDef", into = "MetaFieldDef")]
+pub struct MetaField {
+    /// The name of the field, used as its label name.
+    pub name: String,
+    /// What to do for streams with no value for this field.
+    pub missing: Missing,
+}
+
+/// What to do for streams with no value for a field.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Missing {
+    /// Use the given value.
+    Default(String),
+    /// Do not export the series of the stream.
+    Drop,
+    /// Use the value of the global field of the same name.
+    Inherit,
+}
+
+impl MetaField {
+    /// Create a field whose value is `unspecified` for streams without one.
+    pub fn new(name: impl Into<String>) -> Self {
+        Self { name: name.into(), missing: Missing::Default("unspecified".to_owned()) }
+    }
+}
+
+/// The definition of a [`MetaField`], as written in a metadata file.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+enum MetaFieldDef {
+    Name(String),
+    Table(MetaFieldTable),
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct MetaFieldTable {
+    name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    default: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    missing: Option<MissingPolicy>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum MissingPolicy {
+    Drop,
+    Inherit,
+}
+
+impl TryFrom<MetaFieldDef> for MetaField {
+    type Error = anyhow::Error;
+    fn try_from(def: MetaFieldDef) -> Result<Self> {
+        let table = match def {
+            MetaFieldDef::Name(name) => return Ok(Self::new(name)),
+            MetaFieldDef::Table(table) => table,
+        };
+        let missing = match (table.default, table.missing) {
+            (Some(value), None) => Missing::Default(value),
+            (None, Some(MissingPolicy::Drop)) => Missing::Drop,
+            (None, Some(MissingPolicy::Inherit)) => Missing::Inherit,
+            (None, None) => return Ok(Self::new(table.name)),
+            (Some(_), Some(_)) => {
+                bail!("Meta field {} has both a default and a missing policy", table.name)
+            }
+        };
+        Ok(Self { name: table.name, missing })
+    }
+}
+
+impl From<MetaField> for MetaFieldDef {
+    fn from(field: MetaField) -> Self {
+        let (default, missing) = match field.missing {
+            Missing::Default(value) if value == "unspecified" => {
+                return MetaFieldDef::Name(field.name)
+            }
+            Missing::Default(value) => (Some(value), None),
+            Missing::Drop => (None, Some(MissingPolicy::Drop)),
+            Missing::Inherit => (None, Some(MissingPolicy::Inherit)),
+        };
+        MetaFieldDef::Table(MetaFieldTable { name: field.name, default, missing })
+    }
+}
+
+/// Metadata applied to every stream whose name matches a glob or a regular
+/// expression. Values may refer to the capture groups of the pattern, such as
+/// `$1` or `${name}`, where each wildcard of a glob is a capture group.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(try_from = "MetaPatternDef", into = "MetaPatternDef")]
+pub struct MetaPattern {
+    def: MetaPatternDef,
+    regex: Regex,
+}
+
+/// The definition of a [`MetaPattern`], as written in a metadata file.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct MetaPatternDef {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    glob: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    regex: Option<String>,
+    values: HashMap<String, String>,
+}
+
+impl MetaPattern {
+    /// The value of a field for a stream matching this pattern, with
+    /// references to capture groups replaced.
+    fn value(&self, field: &str, captures: &Captures) -> Option<String> {
+        self.def.values.get(field).map(|template| {
+            let mut value = String::new();
+            captures.expand(template, &mut value);
+            value
+        })
+    }
+}
+
+impl TryFrom<MetaPatternDef> for MetaPattern {
+    type Error = anyhow::Error;
+    fn try_from(def: MetaPatternDef) -> Result<Self> {
+        let regex = match (&def.glob, &def.regex) {
+            (Some(glob), None) => glob_to_regex(glob),
+            (None, Some(regex)) => regex.clone(),
+            _ => bail!("A meta pattern needs exactly one of glob and regex"),
+        };
+        let regex = Regex::new(&regex).context("Invalid meta pattern")?;
+        Ok(Self { def, regex })
+    }
+}
+
+impl From<MetaPattern> for MetaPatternDef {
+    fn from(pattern: MetaPattern) -> Self {
+        pattern.def
+    }
+}
+
+/// Translate a glob into a regular expression matching whole stream names,
+/// where `*` matches any number of characters and `?` matches one character.
+fn glob_to_regex(glob: &str) -> String {
+    let mut regex = String::from("^");
+    for c in glob.chars() {
+        match c {
+            '*' => regex.push_str("(.*)"),
+            '?' => regex.push_str("(.)"),
+            c => regex.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+        }
+    }
+    regex.push('$');
+    regex
+}
+
+/// Check that a field name is a valid Prometheus label name.
+fn validate_label_name(name: &str) -> Result<()> {
+    let mut chars = name.chars();
+    let valid = chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && !name.starts_with("__");
+    if !valid {
+        bail!("Invalid meta field name: {:?}", name);
+    }
+    Ok(())
+}
+
+/// The prefix of environment variables giving metadata.
+const ENV_PREFIX: &str = "RTMP_META_";
+
 /// Enum for the supported formats of metadata file.
-#[derive(Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Format {
     /// The JSON format.
     Json,
     /// The TOML format.
     Toml,
+    /// The YAML format.
+    Yaml,
+}
+
+impl Format {
+    /// Detect the format of a file from its extension, defaulting to JSON.
+    pub fn detect(path: impl AsRef<Path>) -> Self {
+        path.as_ref()
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .and_then(|ext| ext.parse().ok())
+            .unwrap_or(Format::Json)
+    }
 }
 
 impl FromStr for Format {
@@ -132,6 +510,7 @@ impl FromStr for Format {
         match s.to_ascii_lowercase().as_str() {
             "json" => Ok(Format::Json),
             "toml" => Ok(Format::Toml),
+            "yaml" | "yml" => Ok(Format::Yaml),
             _ => bail!("Unknown format: {}", s),
         }
     }
@@ -139,7 +518,231 @@ impl FromStr for Format {
 
 #[cfg(test)]
 mod tests {
-    use super::MetaFile;
+    use std::{collections::HashMap, env, fs};
+
+    use super::{glob_to_regex, Format, MetaField, MetaFile, Missing};
+
+    fn meta(fields: &[&str], values: &[(&str, &str, &str)]) -> MetaFile {
+        let mut file = MetaFile {
+            fields: fields.iter().map(|field| MetaField::new(*field)).collect(),
+            ..Default::default()
+        };
+        for (stream, field, value) in values {
+            file.metadata
+                .entry(stream.to_string())
+                .or_default()
+                .insert(field.to_string(), value.to_string());
+        }
+        file
+    }
+
+    #[test]
+    fn test_validate_meta_file() {
+        assert!(meta(&["owner", "region"], &[("my-stream", "owner", "alice")]).validate().is_ok());
+        // invalid label names
+        assert!(meta(&["owner name"], &[]).validate().is_err());
+        assert!(meta(&["1owner"], &[]).validate().is_err());
+        assert!(meta(&["__owner"], &[]).validate().is_err());
+        // labels used by the exporter
+        assert!(meta(&["stream"], &[]).validate().is_err());
+        assert!(meta(&["codec"], &[]).validate().is_err());
+        // labels the exporter only uses on metrics without metadata
+        assert!(meta(&["version", "path"], &[]).validate().is_ok());
+        // duplicate fields
+        assert!(meta(&["owner", "owner"], &[]).validate().is_err());
+        // values for undeclared fields
+        assert!(meta(&["owner"], &[("my-stream", "region", "eu")]).validate().is_err());
+    }
+
+    const PATTERNS: &str = r#"
+fields = ["customer", "event", "tier"]
+
+[metadata.cust42-event-1234]
+event = "launch"
+
+[[patterns]]
+regex = "^cust(?P<customer>\\d+)-event-(\\d+)$"
+values = { customer = "${customer}", event = "$2" }
+
+[[patterns]]
+glob = "cust*-*"
+values = { customer = "other-$1", tier = "$2" }
+"#;
+
+    #[test]
+    fn test_resolve_patterns() {
+        let file: MetaFile = toml::from_str(PATTERNS).expect("failed to parse meta file");
+        assert!(file.validate().is_ok());
+        // exact values take precedence over patterns, field by field
+        assert_eq!(
+            file.get_values_for("live", "cust42-event-1234").unwrap(),
+            vec!["42", "launch", "1234"]
+        );
+        // earlier patterns take precedence over later ones
+        assert_eq!(file.get_values_for("live", "cust7-event-99").unwrap(), vec!["7", "99", "99"]);
+        // later patterns fill in fields not matched by earlier ones
+        assert_eq!(
+            file.get_values_for("live", "cust7-gold").unwrap(),
+            vec!["other-7", "unspecified", "gold"]
+        );
+        // globs match whole stream names
+        assert_eq!(
+            file.get_values_for("live", "my-cust7-gold").unwrap(),
+            vec!["unspecified", "unspecified", "unspecified"]
+        );
+    }
+
+    #[test]
+    fn test_resolve_scoped() {
+        let file = meta(
+            &["owner", "region"],
+            &[("foo", "owner", "alice"), ("foo", "region", "eu"), ("live/foo", "owner", "bob")],
+        );
+        // application-scoped values take precedence over stream values
+        assert_eq!(file.get_values_for("live", "foo").unwrap(), vec!["bob", "eu"]);
+        assert_eq!(file.get_values_for("backup", "foo").unwrap(), vec!["alice", "eu"]);
+        assert_eq!(file.get_values_for("live", "bar").unwrap(), vec!["unspecified", "unspecified"]);
+    }
+
+    const FIELDS: &str = r#"
+fields = [
+    "owner",
+    { name = "customer", default = "none" },
+    { name = "region", missing = "inherit" },
+    { name = "tier", missing = "drop" },
+]
+globalFields = { region = "eu" }
+
+[metadata.foo]
+tier = "gold"
+
+[metadata.bar]
+owner = "alice"
+region = "us"
+"#;
+
+    #[test]
+    fn test_missing_values() {
+        let file: MetaFile = toml::from_str(FIELDS).expect("failed to parse meta file");
+        assert!(file.validate().is_ok());
+        assert_eq!(file.fields[0], MetaField::new("owner"));
+        assert_eq!(file.fields[1].missing, Missing::Default("none".to_owned()));
+        assert_eq!(file.fields[2].missing, Missing::Inherit);
+        assert_eq!(file.fields[3].missing, Missing::Drop);
+        assert_eq!(
+            file.get_values_for("live", "foo"),
+            Some(vec![
+                "unspecified".to_owned(),
+                "none".to_owned(),
+                "eu".to_owned(),
+                "gold".to_owned()
+            ])
+        );
+        // streams missing a value for a dropped field are not exported
+        assert_eq!(file.get_values_for("live", "bar"), None);
+        assert_eq!(file.get_values_for("live", "baz"), None);
+    }
+
+    #[test]
+    fn test_invalid_fields() {
+        let parse = |fields: &str| toml::from_str::<MetaFile>(&format!("fields = [{}]", fields));
+        // both a default and a missing policy
+        assert!(parse("{ name = \"tier\", default = \"none\", missing = \"drop\" }").is_err());
+        assert!(parse("{ name = \"tier\", missing = \"ignore\" }").is_err());
+        assert!(parse("{ name = \"tier\", value = \"none\" }").is_err());
+        // inheriting from a missing global field
+        let file = parse("{ name = \"region\", missing = \"inherit\" }").unwrap();
+        assert!(file.validate().is_err());
+    }
+
+    #[test]
+    fn test_invalid_global_fields() {
+        let parse = |file: &str| toml::from_str::<MetaFile>(file).unwrap().validate();
+        assert!(parse("globalFields = { region = \"eu\" }\nfields = []").is_ok());
+        // labels of the exporter
+        assert!(parse("globalFields = { stream = \"eu\" }\nfields = []").is_err());
+        assert!(parse("globalFields = { kind = \"eu\" }\nfields = []").is_err());
+        // meta fields not inheriting from the global field
+        assert!(parse("globalFields = { region = \"eu\" }\nfields = [\"region\"]").is_err());
+        assert!(parse(
+            "globalFields = { region = \"eu\" }\nfields = [{ name = \"region\", missing = \"inherit\" }]"
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn test_glob_to_regex() {
+        assert_eq!(glob_to_regex("cust*-event-?"), "^cust(.*)\\-event\\-(.)$");
+        assert_eq!(glob_to_regex("a.b"), "^a\\.b$");
+    }
+
+    #[test]
+    fn test_invalid_patterns() {
+        let parse = |pattern: &str| {
+            toml::from_str::<MetaFile>(&format!(
+                "fields = [\"customer\"]\n[[patterns]]\n{}",
+                pattern
+            ))
+        };
+        assert!(parse("glob = \"cust*\"\nvalues = {}").is_ok());
+        // exactly one of glob and regex
+        assert!(parse("values = {}").is_err());
+        assert!(parse("glob = \"cust*\"\nregex = \"^cust\"\nvalues = {}").is_err());
+        // invalid regular expressions
+        assert!(parse("regex = \"(\"\nvalues = {}").is_err());
+        // values for undeclared fields
+        let file = parse("glob = \"cust*\"\nvalues = { region = \"eu\" }").unwrap();
+        assert!(file.validate().is_err());
+    }
+
+    #[test]
+    fn test_set_stream() {
+        let mut file = meta(&["owner", "region"], &[("my-stream", "owner", "alice")]);
+        let values = HashMap::from([("region".to_owned(), "eu".to_owned())]);
+        let previous = file.set_stream("my-stream", values).unwrap();
+        assert_eq!(previous.unwrap().get("owner").unwrap(), "alice");
+        assert_eq!(file.get_values_for("live", "my-stream").unwrap(), vec!["unspecified", "eu"]);
+        // unknown fields leave the metadata unchanged
+        let values = HashMap::from([("customer".to_owned(), "acme".to_owned())]);
+        assert!(file.set_stream("my-stream", values).is_err());
+        assert_eq!(file.get_values_for("live", "my-stream").unwrap(), vec!["unspecified", "eu"]);
+        assert!(file.remove_stream("my-stream").is_some());
+        assert!(file.remove_stream("my-stream").is_none());
+    }
+
+    #[test]
+    fn test_save_meta_file() {
+        for format in [Format::Json, Format::Toml, Format::Yaml] {
+            let path = env::temp_dir().join(format!(
+                "nginx-rtmp-exporter-test-meta-{}.{:?}",
+                std::process::id(),
+                format
+            ));
+            let mut file = meta(&["owner"], &[("my-stream", "owner", "alice")]);
+            file.global_fields = Some(HashMap::from([("region".to_owned(), "eu".to_owned())]));
+            file.fields.push(MetaField { name: "region".to_owned(), missing: Missing::Inherit });
+            file.fields.push(MetaField {
+                name: "customer".to_owned(),
+                missing: Missing::Default("none".to_owned()),
+            });
+            file.save(&path, format).unwrap();
+            let saved = MetaFile::from_path(&path, format).unwrap();
+            fs::remove_file(&path).ok();
+            assert_eq!(saved.fields, file.fields);
+            assert_eq!(saved.global_fields, file.global_fields);
+            assert_eq!(saved.metadata, file.metadata);
+            // patterns are saved as they were written
+            let file: MetaFile = toml::from_str(PATTERNS).unwrap();
+            file.save(&path, format).unwrap();
+            let saved = MetaFile::from_path(&path, format).unwrap();
+            fs::remove_file(&path).ok();
+            assert_eq!(saved.patterns.len(), 2);
+            assert_eq!(
+                saved.get_values_for("live", "cust7-gold").unwrap(),
+                file.get_values_for("live", "cust7-gold").unwrap()
+            );
+        }
+    }
 
     #[test]
     fn test_parse_meta_file_toml() {
@@ -151,7 +754,7 @@ eaf8409c-6ee0-456b-aef8-d3477e6c5fdc = { message = "hello" }
 "#;
         let file: MetaFile = toml::from_str(file).expect("failed to parse meta file");
         println!("{:?}", file);
-        assert_eq!(file.fields, vec!["message"]);
+        assert_eq!(file.get_fields(), vec!["message"]);
         assert_eq!(file.metadata.len(), 1);
         assert_eq!(
             file.metadata
@@ -170,6 +773,58 @@ eaf8409c-6ee0-456b-aef8-d3477e6c5fdc = { message = "hello" }
         );
     }
 
+    #[test]
+    fn test_parse_meta_file_yaml() {
+        let file = r#"
+fields: [message]
+metadata:
+  eaf8409c-6ee0-456b-aef8-d3477e6c5fdc:
+    message: hello
+"#;
+        let file: MetaFile = serde_yaml::from_str(file).expect("failed to parse yaml");
+        assert_eq!(file.get_fields(), vec!["message"]);
+        assert_eq!(
+            file.get_values_for("live", "eaf8409c-6ee0-456b-aef8-d3477e6c5fdc").unwrap(),
+            vec!["hello"]
+        );
+    }
+
+    #[test]
+    fn test_meta_file_from_vars() {
+        let vars = |vars: &[(&str, &str)]| {
+            MetaFile::from_vars(
+                vars.iter().map(|(key, value)| (key.to_string(), value.to_string())),
+            )
+        };
+        let file = vars(&[
+            ("RTMP_META_FIELDS", "customer, event_id"),
+            ("RTMP_META_cust42-event_customer", "acme"),
+            ("RTMP_META_cust42-event_event_id", "1234"),
+            ("RTMP_META_live/my_stream_customer", "globex"),
+            ("HOME", "/root"),
+        ])
+        .unwrap();
+        assert_eq!(file.get_fields(), vec!["customer", "event_id"]);
+        assert_eq!(file.get_values_for("live", "cust42-event").unwrap(), vec!["acme", "1234"]);
+        // stream names may contain underscores and be scoped by application
+        assert_eq!(
+            file.get_values_for("live", "my_stream").unwrap(),
+            vec!["globex", "unspecified"]
+        );
+        // values for undeclared fields
+        assert!(vars(&[("RTMP_META_my-stream_customer", "acme")]).is_err());
+        assert!(vars(&[("RTMP_META_FIELDS", "customer"), ("RTMP_META_customer", "acme")]).is_err());
+    }
+
+    #[test]
+    fn test_detect_format() {
+        assert_eq!(Format::detect("meta.toml"), Format::Toml);
+        assert_eq!(Format::detect("meta.yaml"), Format::Yaml);
+        assert_eq!(Format::detect("meta.YML"), Format::Yaml);
+        assert_eq!(Format::detect("meta.json"), Format::Json);
+        assert_eq!(Format::detect("meta"), Format::Json);
+    }
+
     #[test]
     fn test_parse_meta_file_json() {
         let file = r#"{
@@ -182,7 +837,7 @@ eaf8409c-6ee0-456b-aef8-d3477e6c5fdc = { message = "hello" }
 }"#;
         let file: MetaFile = serde_json::from_str(file).expect("failed to parse json");
         println!("{:?}", file);
-        assert_eq!(file.fields, vec!["message"]);
+        assert_eq!(file.get_fields(), vec!["message"]);
         assert_eq!(file.metadata.len(), 1);
         assert_eq!(
             file.metadata
@@ -1,200 +1,716 @@
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 use anyhow::{Context as AnyhowContext, Result};
-use prometheus::{labels, opts, IntGauge, IntGaugeVec, Opts};
+use prometheus::{
+    core::Collector, labels, opts, Gauge, GaugeVec, IntCounter, IntCounterVec, IntGauge,
+    IntGaugeVec, Opts, Registry,
+};
 
 use crate::meta::MetaFile;
 
 #[derive(Debug)]
 pub struct MetricContext {
     pub nginx_build_info: IntGaugeVec,
-    pub nginx_rtmp_application_count: IntGauge,
+    pub nginx_rtmp_application_count: IntGaugeVec,
+    pub nginx_rtmp_application_info: IntGaugeVec,
     pub nginx_rtmp_active_streams: IntGaugeVec,
-    pub nginx_rtmp_incoming_bytes_total: IntGauge,
-    pub nginx_rtmp_outgoing_bytes_total: IntGauge,
-    pub nginx_rtmp_incoming_bandwidth: IntGauge,
-    pub nginx_rtmp_outgoing_bandwidth: IntGauge,
-    pub nginx_rtmp_stream_incoming_bytes_total: IntGaugeVec,
-    pub nginx_rtmp_stream_outgoing_bytes_total: IntGaugeVec,
+    pub nginx_rtmp_play_active_streams: IntGaugeVec,
+    pub nginx_rtmp_play_clients: IntGaugeVec,
+    pub nginx_rtmp_play_outgoing_bytes_total: IntCounterVec,
+    pub nginx_rtmp_incoming_bytes_total: IntCounterVec,
+    pub nginx_rtmp_outgoing_bytes_total: IntCounterVec,
+    pub nginx_rtmp_uptime_seconds: IntGaugeVec,
+    pub nginx_rtmp_start_time_seconds: GaugeVec,
+    pub nginx_rtmp_restarts_total: IntCounterVec,
+    pub nginx_rtmp_connections_accepted_total: IntCounterVec,
+    pub nginx_rtmp_incoming_bandwidth: IntGaugeVec,
+    pub nginx_rtmp_outgoing_bandwidth: IntGaugeVec,
+    pub nginx_rtmp_stream_incoming_bytes_total: IntCounterVec,
+    pub nginx_rtmp_stream_outgoing_bytes_total: IntCounterVec,
     pub nginx_rtmp_stream_incoming_bandwidth: IntGaugeVec,
     pub nginx_rtmp_stream_outgoing_bandwidth: IntGaugeVec,
     pub nginx_rtmp_stream_bandwidth_video: IntGaugeVec,
     pub nginx_rtmp_stream_bandwidth_audio: IntGaugeVec,
     pub nginx_rtmp_stream_publisher_avsync: IntGaugeVec,
     pub nginx_rtmp_stream_total_clients: IntGaugeVec,
+    pub nginx_rtmp_stream_publishers: IntGaugeVec,
+    pub nginx_rtmp_stream_viewers: IntGaugeVec,
+    pub nginx_rtmp_stream_relays: IntGaugeVec,
+    pub nginx_rtmp_stream_video_info: IntGaugeVec,
+    pub nginx_rtmp_stream_video_width_pixels: IntGaugeVec,
+    pub nginx_rtmp_stream_video_height_pixels: IntGaugeVec,
+    pub nginx_rtmp_stream_video_frame_rate: GaugeVec,
+    pub nginx_rtmp_stream_audio_info: IntGaugeVec,
+    pub nginx_rtmp_stream_audio_channels: IntGaugeVec,
+    pub nginx_rtmp_stream_audio_sample_rate_hertz: IntGaugeVec,
+    pub nginx_rtmp_stream_recording: IntGaugeVec,
+    pub nginx_rtmp_stream_recorded_bytes: IntGaugeVec,
+    pub nginx_rtmp_relay_info: IntGaugeVec,
+    pub nginx_rtmp_relay_incoming_bytes_total: IntCounterVec,
+    pub nginx_rtmp_relay_outgoing_bytes_total: IntCounterVec,
+    pub nginx_rtmp_client_connected_seconds: GaugeVec,
+    pub nginx_rtmp_client_dropped_frames_total: IntCounterVec,
+    pub nginx_rtmp_client_info: IntGaugeVec,
+    pub nginx_rtmp_exporter_parse_errors_total: IntCounterVec,
+    pub nginx_rtmp_up: IntGaugeVec,
+    pub nginx_rtmp_scrape_duration_seconds: GaugeVec,
+    pub nginx_rtmp_scrape_errors_total: IntCounterVec,
+    pub nginx_rtmp_last_successful_scrape_timestamp_seconds: GaugeVec,
+}
+
+/// The labels the exporter gives the series that metadata fields are added to,
+/// which metadata fields may not use.
+pub const BUILTIN_LABELS: &[&str] = &[
+    "target",
+    "server",
+    "application",
+    "stream",
+    "codec",
+    "profile",
+    "level",
+    "recorder",
+    "direction",
+    "peer",
+    "client",
+    "flashver",
+    "pageurl",
+    "role",
+];
+
+/// The labels the exporter gives its other metrics, which global fields may
+/// not use either.
+pub const EXPORTER_LABELS: &[&str] = &[
+    "version",
+    "compiler",
+    "rtmp_version",
+    "live",
+    "play",
+    "record",
+    "path",
+    "kind",
+    "field",
+    "value",
+];
+
+/// Metrics describing the metadata the exporter has loaded.
+#[derive(Debug)]
+pub struct ExporterInfo {
+    metadata_fields: IntGaugeVec,
+    metadata_values: IntGaugeVec,
+}
+
+impl ExporterInfo {
+    /// Replace the exported metadata fields and values.
+    pub fn set_metadata(&self, metadata: &MetaFile) {
+        self.metadata_fields.reset();
+        metadata.get_fields().iter().for_each(|field| {
+            self.metadata_fields.with_label_values(&[field]).set(1);
+        });
+        self.metadata_values.reset();
+        metadata.entries().iter().for_each(|(stream, field, value)| {
+            self.metadata_values
+                .with_label_values(&[stream.as_str(), field.as_str(), value.as_str()])
+                .set(1);
+        });
+    }
+}
+
+/// Metrics tracking the freshness of the metadata. These are kept in their own
+/// registry, as they outlive the registry of the NGINX metrics.
+#[derive(Debug)]
+pub struct MetadataStatus {
+    pub registry: Registry,
+    reload_success: IntGauge,
+    last_reload_success: Gauge,
+    reload_failures: IntCounter,
+}
+
+impl MetadataStatus {
+    /// Register the metadata freshness metrics in a new registry. Nothing is
+    /// recorded until the metadata has been loaded.
+    pub fn new() -> Result<Self> {
+        let registry = Registry::new();
+        let reload_success = MetricContext::register(
+            &registry,
+            IntGauge::new(
+                "nginx_rtmp_exporter_metadata_reload_success",
+                "A metric with either '0' or '1', tracking whether the last reload of the metadata succeeded.",
+            )?,
+        )?;
+        let last_reload_success = MetricContext::register(
+            &registry,
+            Gauge::new(
+                "nginx_rtmp_exporter_metadata_last_reload_success_timestamp_seconds",
+                "A metric tracking the Unix time at which the metadata was last loaded successfully.",
+            )?,
+        )?;
+        let reload_failures = MetricContext::register(
+            &registry,
+            IntCounter::new(
+                "nginx_rtmp_exporter_metadata_reload_failures_total",
+                "A metric tracking the number of failed reloads of the metadata.",
+            )?,
+        )?;
+        Ok(Self { registry, reload_success, last_reload_success, reload_failures })
+    }
+
+    /// Record the outcome of loading the metadata.
+    pub fn record(&self, success: bool) {
+        self.reload_success.set(success as i64);
+        if success {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+            self.last_reload_success.set(now.as_secs_f64());
+        } else {
+            self.reload_failures.inc();
+        }
+    }
+}
+
+/// Borrow a slice of owned label values.
+pub fn label_values(labels: &[String]) -> Vec<&str> {
+    labels.iter().map(String::as_str).collect()
+}
+
+/// The global fields of the metadata, given to metrics as constant labels.
+#[derive(Debug, Default)]
+struct GlobalLabels {
+    values: HashMap<String, String>,
+    /// The global fields that metadata fields inherit from.
+    inherited: Vec<String>,
+}
+
+impl GlobalLabels {
+    fn new(metadata: &MetaFile) -> Self {
+        Self {
+            values: metadata.global_fields.clone().unwrap_or_default(),
+            inherited: metadata.inherited_fields().map(str::to_owned).collect(),
+        }
+    }
+}
+
+/// The global labels to give a metric. A metadata field inheriting from the
+/// global field of the same name takes the place of the global label on the
+/// metrics it is added to.
+fn const_labels(global_labels: &GlobalLabels, labels: &[&str]) -> HashMap<String, String> {
+    global_labels
+        .values
+        .iter()
+        .filter(|(name, _)| {
+            !(labels.contains(&name.as_str()) && global_labels.inherited.contains(name))
+        })
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect()
 }
 
 impl MetricContext {
+    /// Register a collector in the given registry, returning a handle to it.
+    fn register<C: Collector + Clone + 'static>(registry: &Registry, metric: C) -> Result<C> {
+        registry.register(Box::new(metric.clone())).context("failed to register metric")?;
+        Ok(metric)
+    }
+
     /// Register a vector of integer gauges.
     ///
     /// TODO: These methods do a horrific amount of cloning for no good reason -
     /// pull request to upstream crate?
     fn register_int_gauge_vec(
+        registry: &Registry,
         name: &'static str,
         description: &'static str,
-        global_labels: &HashMap<String, String>,
+        global_labels: &GlobalLabels,
         labels: &[&str],
     ) -> Result<IntGaugeVec> {
-        let opts = Opts::new(name, description).const_labels(global_labels.clone());
-        let labels: Vec<&str> = labels.iter().map(|x| &**x).collect();
-        prometheus::register_int_gauge_vec!(opts, &labels).context("failed to create int gauge vec")
+        let opts = Opts::new(name, description).const_labels(const_labels(global_labels, labels));
+        let metric = IntGaugeVec::new(opts, labels).context("failed to create int gauge vec")?;
+        Self::register(registry, metric)
     }
 
-    /// Register an integer gauge.
-    fn register_int_gauge(
+    /// Register a vector of gauges.
+    fn register_gauge_vec(
+        registry: &Registry,
         name: &'static str,
         description: &'static str,
-        global_labels: &HashMap<String, String>,
+        global_labels: &GlobalLabels,
         labels: &[&str],
-    ) -> Result<IntGauge> {
-        let labels: Vec<String> = labels.iter().map(|x| x.to_string()).collect();
-        let opts = Opts::new(name, description)
-            .const_labels(global_labels.clone())
-            .variable_labels(labels);
-        prometheus::register_int_gauge!(opts).context("failed to create int gauge")
+    ) -> Result<GaugeVec> {
+        let opts = Opts::new(name, description).const_labels(const_labels(global_labels, labels));
+        let metric = GaugeVec::new(opts, labels).context("failed to create gauge vec")?;
+        Self::register(registry, metric)
     }
 
-    pub fn from_metadata(metadata: &MetaFile) -> Result<Self> {
+    /// Register a vector of integer counters.
+    fn register_int_counter_vec(
+        registry: &Registry,
+        name: &'static str,
+        description: &'static str,
+        global_labels: &GlobalLabels,
+        labels: &[&str],
+    ) -> Result<IntCounterVec> {
+        let opts = Opts::new(name, description).const_labels(const_labels(global_labels, labels));
+        let metric =
+            IntCounterVec::new(opts, labels).context("failed to create int counter vec")?;
+        Self::register(registry, metric)
+    }
+
+    /// Register metrics describing the exporter itself, rather than any NGINX
+    /// target.
+    pub fn register_exporter_info(
+        registry: &Registry,
+        metadata: &MetaFile,
+    ) -> Result<ExporterInfo> {
         // register build info gauge
-        prometheus::register_gauge!(opts!(
+        let build_info = Gauge::with_opts(opts!(
 			"nginx_rtmp_exporter_build_info",
 			"A metric with constant value '1', labelled with nginx-rtmp-exporter's build information.",
 			labels! {
 				"version" => env!("VERGEN_GIT_SEMVER"),
 				"rustc_version" => env!("VERGEN_RUSTC_SEMVER"),
 			}
-		))
-        .unwrap()
-        .set(1.0);
+		))?;
+        build_info.set(1.0);
+        Self::register(registry, build_info)?;
 
-        let global_labels = metadata.global_fields.clone().unwrap_or_default();
+        let global_labels = GlobalLabels::new(metadata);
 
         // export metadata fields as metric
-        let field_metric = Self::register_int_gauge_vec(
+        let metadata_fields = Self::register_int_gauge_vec(
+            registry,
             "nginx_rtmp_exporter_metadata_fields",
             "A metric with constant value '1', labelled with available metadata fields.",
             &global_labels,
             &["field"],
         )?;
 
-        // TODO - this can panic
-        metadata.get_fields().iter().for_each(|field| {
-            field_metric.with_label_values(&[field.as_str()]).set(1);
-        });
-
         // export metadata values as metric
-        let value_metric = Self::register_int_gauge_vec(
+        let metadata_values = Self::register_int_gauge_vec(
+            registry,
             "nginx_rtmp_exporter_metadata_values",
             "A metric with constant value '1', labelled with available metadata values.",
             &global_labels,
             &["stream", "field", "value"],
         )?;
 
-        metadata.entries().iter().for_each(|(stream, field, value)| {
-            value_metric
-                .with_label_values(&[stream.as_str(), field.as_str(), value.as_str()])
-                .set(1);
-        });
+        let info = ExporterInfo { metadata_fields, metadata_values };
+        info.set_metadata(metadata);
+        Ok(info)
+    }
+
+    /// Register the NGINX metrics in the given registry.
+    pub fn from_metadata(registry: &Registry, metadata: &MetaFile) -> Result<Self> {
+        let global_labels = GlobalLabels::new(metadata);
 
         // create stream labels
-        let mut labels = vec!["application", "stream"];
-        metadata.get_fields().iter().for_each(|str| {
-            labels.push(str.as_str());
-        });
+        let mut labels = vec!["target", "server", "application", "stream"];
+        labels.extend(metadata.get_fields());
         let labels = &labels;
 
+        // create codec info labels
+        let mut video_info_labels = labels.clone();
+        video_info_labels.extend(["codec", "profile", "level"]);
+        let mut audio_info_labels = labels.clone();
+        audio_info_labels.extend(["codec", "profile"]);
+
+        // create recorder labels
+        let mut recorder_labels = labels.clone();
+        recorder_labels.push("recorder");
+
+        // create relay labels
+        let mut relay_labels = labels.clone();
+        relay_labels.extend(["direction", "peer"]);
+
+        // create client labels
+        let mut client_labels = labels.clone();
+        client_labels.push("client");
+        let mut client_info_labels = client_labels.clone();
+        client_info_labels.extend(["flashver", "pageurl", "role"]);
+
         Ok(Self {
             nginx_build_info: Self::register_int_gauge_vec(
+                registry,
                 "nginx_build_info",
                 "A metric with either '0' or '1', labelled with NGINX's build info when available.",
 				&global_labels,
-                &["version", "compiler", "rtmp_version"]
+                &["target", "version", "compiler", "rtmp_version"]
             )?,
-			nginx_rtmp_application_count: Self::register_int_gauge(
+			nginx_rtmp_application_count: Self::register_int_gauge_vec(
+				registry,
 				"nginx_rtmp_application_count",
 				"A metric tracking the number of NGINX RTMP applications.",
 				&global_labels,
-				&[]
+				&["target"]
+			)?,
+			nginx_rtmp_application_info: Self::register_int_gauge_vec(
+				registry,
+				"nginx_rtmp_application_info",
+				"A metric with constant value '1', labelled with the sections enabled in each NGINX RTMP application.",
+				&global_labels,
+				&["target", "server", "application", "live", "play", "record"]
 			)?,
 			nginx_rtmp_active_streams: Self::register_int_gauge_vec(
+				registry,
 				"nginx_rtmp_active_streams",
-				"A metric tracking the number of active RTMP streams, labelled by application.",
+				"A metric tracking the number of active RTMP streams, labelled by target, server and application.",
+				&global_labels,
+				&["target", "server", "application"]
+			)?,
+			nginx_rtmp_play_active_streams: Self::register_int_gauge_vec(
+				registry,
+				"nginx_rtmp_play_active_streams",
+				"A metric tracking the number of streams being played from disk or HTTP, labelled by target, server and application.",
+				&global_labels,
+				&["target", "server", "application"]
+			)?,
+			nginx_rtmp_play_clients: Self::register_int_gauge_vec(
+				registry,
+				"nginx_rtmp_play_clients",
+				"A metric tracking the number of clients playing streams from disk or HTTP, labelled by target, server and application.",
+				&global_labels,
+				&["target", "server", "application"]
+			)?,
+			nginx_rtmp_play_outgoing_bytes_total: Self::register_int_counter_vec(
+				registry,
+				"nginx_rtmp_play_outgoing_bytes_total",
+				"A metric tracking the total bytes sent to clients playing streams from disk or HTTP, labelled by target, server and application. Bytes sent to a client between the last scrape and it disconnecting are not counted.",
 				&global_labels,
-				&["application"]
+				&["target", "server", "application"]
 			)?,
-            nginx_rtmp_incoming_bytes_total: Self::register_int_gauge(
+            nginx_rtmp_incoming_bytes_total: Self::register_int_counter_vec(
+                registry,
                 "nginx_rtmp_incoming_bytes_total",
                 "A metric tracking the total number of incoming bytes processed.",
 				&global_labels,
-				&[]
+				&["target"]
 			)?,
-            nginx_rtmp_outgoing_bytes_total: Self::register_int_gauge(
+            nginx_rtmp_outgoing_bytes_total: Self::register_int_counter_vec(
+                registry,
                 "nginx_rtmp_outgoing_bytes_total",
                 "A metric tracking the total number of outgoing bytes processed.",
 				&global_labels,
-				&[]
+				&["target"]
+			)?,
+			nginx_rtmp_uptime_seconds: Self::register_int_gauge_vec(
+				registry,
+				"nginx_rtmp_uptime_seconds",
+				"A metric tracking the uptime of the NGINX server, in seconds.",
+				&global_labels,
+				&["target"]
+			)?,
+			nginx_rtmp_start_time_seconds: Self::register_gauge_vec(
+				registry,
+				"nginx_rtmp_start_time_seconds",
+				"A metric tracking the UNIX timestamp at which the NGINX server started, derived from its uptime.",
+				&global_labels,
+				&["target"]
+			)?,
+			nginx_rtmp_restarts_total: Self::register_int_counter_vec(
+				registry,
+				"nginx_rtmp_restarts_total",
+				"A metric counting the restarts of the NGINX server observed between scrapes.",
+				&global_labels,
+				&["target"]
 			)?,
-            nginx_rtmp_incoming_bandwidth: Self::register_int_gauge(
+			nginx_rtmp_connections_accepted_total: Self::register_int_counter_vec(
+				registry,
+				"nginx_rtmp_connections_accepted_total",
+				"A metric tracking the total number of connections accepted by the NGINX server.",
+				&global_labels,
+				&["target"]
+			)?,
+            nginx_rtmp_incoming_bandwidth: Self::register_int_gauge_vec(
+                registry,
                 "nginx_rtmp_incoming_bandwidth",
                 "A metric tracking the incoming bandwidth to the server.",
 				&global_labels,
-				&[]
+				&["target"]
 			)?,
-            nginx_rtmp_outgoing_bandwidth: Self::register_int_gauge(
+            nginx_rtmp_outgoing_bandwidth: Self::register_int_gauge_vec(
+                registry,
                 "nginx_rtmp_outgoing_bandwidth",
                 "A metric tracking the outgoing bandwidth from the server.",
 				&global_labels,
-				&[]
+				&["target"]
 			)?,
 
-            nginx_rtmp_stream_incoming_bytes_total: Self::register_int_gauge_vec(
+            nginx_rtmp_stream_incoming_bytes_total: Self::register_int_counter_vec(
+				registry,
 				"nginx_rtmp_stream_incoming_bytes_total",
 				"A metric tracking the total received bytes from a stream, labelled by stream and application.",
 				&global_labels,
                 labels
             )?,
-            nginx_rtmp_stream_outgoing_bytes_total: Self::register_int_gauge_vec(
+            nginx_rtmp_stream_outgoing_bytes_total: Self::register_int_counter_vec(
+				registry,
 				"nginx_rtmp_stream_outgoing_bytes_total",
 				"A metric tracking the total sent bytes by a given stream, labelled by stream and application.",
                 &global_labels,
 				labels
             )?,
             nginx_rtmp_stream_incoming_bandwidth: Self::register_int_gauge_vec(
+				registry,
 				"nginx_rtmp_stream_incoming_bandwidth",
 				"A metric tracking the incoming bandwidth of a given stream, labelled by stream and application.",
                 &global_labels,
 				labels
             )?,
             nginx_rtmp_stream_outgoing_bandwidth: Self::register_int_gauge_vec(
+				registry,
 				"nginx_rtmp_stream_outgoing_bandwidth",
 				"A metric tracking the outgoing bandwidth of a given stream, labelled by stream and application.",
                 &global_labels,
 				labels
             )?,
 			nginx_rtmp_stream_bandwidth_video: Self::register_int_gauge_vec(
+				registry,
 				"nginx_rtmp_stream_bandwidth_video",
 				"A metric tracking the video bandwidth of a given stream, labelled by stream and application.",
                 &global_labels,
 				labels
 			)?,
 			nginx_rtmp_stream_bandwidth_audio: Self::register_int_gauge_vec(
+				registry,
 				"nginx_rtmp_stream_bandwidth_audio",
 				"A metric tracking the audio bandwidth of a given stream, labelled by stream and application.",
                 &global_labels,
 				labels
 			)?,
 			nginx_rtmp_stream_publisher_avsync: Self::register_int_gauge_vec(
+				registry,
 				"nginx_rtmp_stream_publisher_avsync",
 				"A metric tracking the A-V sync value of a given stream, labelled by stream and application.",
 				&global_labels,
 				labels
 			)?,
 			nginx_rtmp_stream_total_clients: Self::register_int_gauge_vec(
+				registry,
 				"nginx_rtmp_stream_total_clients",
-				"A metric tracking the number of clients connected to a given stream, labelled by stream and application.",
+				"A metric tracking the number of publishers and viewers connected to a given stream, excluding relays, labelled by stream and application.",
+				&global_labels,
+				labels
+			)?,
+			nginx_rtmp_stream_publishers: Self::register_int_gauge_vec(
+				registry,
+				"nginx_rtmp_stream_publishers",
+				"A metric tracking the number of clients publishing a given stream, excluding relays, labelled by stream and application.",
+				&global_labels,
+				labels
+			)?,
+			nginx_rtmp_stream_viewers: Self::register_int_gauge_vec(
+				registry,
+				"nginx_rtmp_stream_viewers",
+				"A metric tracking the number of clients playing a given stream, excluding relays, labelled by stream and application.",
+				&global_labels,
+				labels
+			)?,
+			nginx_rtmp_stream_relays: Self::register_int_gauge_vec(
+				registry,
+				"nginx_rtmp_stream_relays",
+				"A metric tracking the number of relays connected to a given stream, labelled by stream and application.",
+				&global_labels,
+				labels
+			)?,
+			nginx_rtmp_stream_video_info: Self::register_int_gauge_vec(
+				registry,
+				"nginx_rtmp_stream_video_info",
+				"A metric with constant value '1', labelled with the video codec, profile and level of a given stream.",
+				&global_labels,
+				&video_info_labels
+			)?,
+			nginx_rtmp_stream_video_width_pixels: Self::register_int_gauge_vec(
+				registry,
+				"nginx_rtmp_stream_video_width_pixels",
+				"A metric tracking the video width of a given stream, labelled by stream and application.",
+				&global_labels,
+				labels
+			)?,
+			nginx_rtmp_stream_video_height_pixels: Self::register_int_gauge_vec(
+				registry,
+				"nginx_rtmp_stream_video_height_pixels",
+				"A metric tracking the video height of a given stream, labelled by stream and application.",
+				&global_labels,
+				labels
+			)?,
+			nginx_rtmp_stream_video_frame_rate: Self::register_gauge_vec(
+				registry,
+				"nginx_rtmp_stream_video_frame_rate",
+				"A metric tracking the video frame rate of a given stream, labelled by stream and application.",
+				&global_labels,
+				labels
+			)?,
+			nginx_rtmp_stream_audio_info: Self::register_int_gauge_vec(
+				registry,
+				"nginx_rtmp_stream_audio_info",
+				"A metric with constant value '1', labelled with the audio codec and profile of a given stream.",
+				&global_labels,
+				&audio_info_labels
+			)?,
+			nginx_rtmp_stream_audio_channels: Self::register_int_gauge_vec(
+				registry,
+				"nginx_rtmp_stream_audio_channels",
+				"A metric tracking the number of audio channels of a given stream, labelled by stream and application.",
 				&global_labels,
 				labels
 			)?,
+			nginx_rtmp_stream_audio_sample_rate_hertz: Self::register_int_gauge_vec(
+				registry,
+				"nginx_rtmp_stream_audio_sample_rate_hertz",
+				"A metric tracking the audio sample rate of a given stream, labelled by stream and application.",
+				&global_labels,
+				labels
+			)?,
+			nginx_rtmp_stream_recording: Self::register_int_gauge_vec(
+				registry,
+				"nginx_rtmp_stream_recording",
+				"A metric with either '0' or '1', tracking whether a recorder is writing a given stream to disk, labelled by stream, application and recorder.",
+				&global_labels,
+				&recorder_labels
+			)?,
+			nginx_rtmp_stream_recorded_bytes: Self::register_int_gauge_vec(
+				registry,
+				"nginx_rtmp_stream_recorded_bytes",
+				"A metric tracking the bytes written to the current recording of a given stream, labelled by stream, application and recorder.",
+				&global_labels,
+				&recorder_labels
+			)?,
+			nginx_rtmp_relay_info: Self::register_int_gauge_vec(
+				registry,
+				"nginx_rtmp_relay_info",
+				"A metric with constant value '1', labelled with the direction and peer address of a relay of a given stream.",
+				&global_labels,
+				&relay_labels
+			)?,
+			nginx_rtmp_relay_incoming_bytes_total: Self::register_int_counter_vec(
+				registry,
+				"nginx_rtmp_relay_incoming_bytes_total",
+				"A metric tracking the total bytes received by a relay of a given stream, labelled by stream, application, direction and peer.",
+				&global_labels,
+				&relay_labels
+			)?,
+			nginx_rtmp_relay_outgoing_bytes_total: Self::register_int_counter_vec(
+				registry,
+				"nginx_rtmp_relay_outgoing_bytes_total",
+				"A metric tracking the total bytes sent by a relay of a given stream, labelled by stream, application, direction and peer.",
+				&global_labels,
+				&relay_labels
+			)?,
+			nginx_rtmp_client_connected_seconds: Self::register_gauge_vec(
+				registry,
+				"nginx_rtmp_client_connected_seconds",
+				"A metric tracking how long a client has been connected to a given stream, labelled by stream, application and client id.",
+				&global_labels,
+				&client_labels
+			)?,
+			nginx_rtmp_client_dropped_frames_total: Self::register_int_counter_vec(
+				registry,
+				"nginx_rtmp_client_dropped_frames_total",
+				"A metric tracking the total frames dropped by a client of a given stream, labelled by stream, application and client id.",
+				&global_labels,
+				&client_labels
+			)?,
+			nginx_rtmp_client_info: Self::register_int_gauge_vec(
+				registry,
+				"nginx_rtmp_client_info",
+				"A metric with constant value '1', labelled with the flash version, page URL and role of a client of a given stream.",
+				&global_labels,
+				&client_info_labels
+			)?,
+			nginx_rtmp_exporter_parse_errors_total: Self::register_int_counter_vec(
+				registry,
+				"nginx_rtmp_exporter_parse_errors_total",
+				"A metric counting malformed elements skipped while parsing the statistics of a target, labelled by the path of the element.",
+				&global_labels,
+				&["target", "path"]
+			)?,
+			nginx_rtmp_up: Self::register_int_gauge_vec(
+				registry,
+				"nginx_rtmp_up",
+				"A metric with either '0' or '1', indicating whether the last scrape of a target succeeded.",
+				&global_labels,
+				&["target"]
+			)?,
+			nginx_rtmp_scrape_duration_seconds: Self::register_gauge_vec(
+				registry,
+				"nginx_rtmp_scrape_duration_seconds",
+				"A metric tracking the duration of the last scrape of a target, in seconds.",
+				&global_labels,
+				&["target"]
+			)?,
+			nginx_rtmp_scrape_errors_total: Self::register_int_counter_vec(
+				registry,
+				"nginx_rtmp_scrape_errors_total",
+				"A metric counting failed scrapes of a target, labelled by the kind of error.",
+				&global_labels,
+				&["target", "kind"]
+			)?,
+			nginx_rtmp_last_successful_scrape_timestamp_seconds: Self::register_gauge_vec(
+				registry,
+				"nginx_rtmp_last_successful_scrape_timestamp_seconds",
+				"A metric tracking the UNIX timestamp of the last successful scrape of a target.",
+				&global_labels,
+				&["target"]
+			)?,
         })
     }
 }
+
+impl MetricContext {
+    /// Remove the series of the play section of an application.
+    pub fn remove_play(&self, labels: &[&str]) {
+        self.nginx_rtmp_play_active_streams.remove_label_values(labels).ok();
+        self.nginx_rtmp_play_clients.remove_label_values(labels).ok();
+        self.nginx_rtmp_play_outgoing_bytes_total.remove_label_values(labels).ok();
+    }
+
+    /// Remove every per-stream series with the given label values.
+    pub fn remove_stream(&self, labels: &[&str]) {
+        // not every series exists for every stream, so missing series are ignored
+        self.nginx_rtmp_stream_incoming_bytes_total.remove_label_values(labels).ok();
+        self.nginx_rtmp_stream_outgoing_bytes_total.remove_label_values(labels).ok();
+        self.nginx_rtmp_stream_incoming_bandwidth.remove_label_values(labels).ok();
+        self.nginx_rtmp_stream_outgoing_bandwidth.remove_label_values(labels).ok();
+        self.nginx_rtmp_stream_bandwidth_video.remove_label_values(labels).ok();
+        self.nginx_rtmp_stream_bandwidth_audio.remove_label_values(labels).ok();
+        self.nginx_rtmp_stream_publisher_avsync.remove_label_values(labels).ok();
+        self.nginx_rtmp_stream_total_clients.remove_label_values(labels).ok();
+        self.nginx_rtmp_stream_publishers.remove_label_values(labels).ok();
+        self.nginx_rtmp_stream_viewers.remove_label_values(labels).ok();
+        self.nginx_rtmp_stream_relays.remove_label_values(labels).ok();
+        self.remove_video(labels);
+        self.remove_audio(labels);
+    }
+
+    /// Remove the video dimension series of a stream, except for video info.
+    pub fn remove_video(&self, labels: &[&str]) {
+        self.nginx_rtmp_stream_video_width_pixels.remove_label_values(labels).ok();
+        self.nginx_rtmp_stream_video_height_pixels.remove_label_values(labels).ok();
+        self.nginx_rtmp_stream_video_frame_rate.remove_label_values(labels).ok();
+    }
+
+    /// Remove the audio series of a stream, except for audio info.
+    pub fn remove_audio(&self, labels: &[&str]) {
+        self.nginx_rtmp_stream_audio_channels.remove_label_values(labels).ok();
+        self.nginx_rtmp_stream_audio_sample_rate_hertz.remove_label_values(labels).ok();
+    }
+
+    /// Remove the series of a recorder of a stream.
+    pub fn remove_recorder(&self, labels: &[&str]) {
+        self.nginx_rtmp_stream_recording.remove_label_values(labels).ok();
+        self.nginx_rtmp_stream_recorded_bytes.remove_label_values(labels).ok();
+    }
+
+    /// Remove the series of a relay of a stream.
+    pub fn remove_relay(&self, labels: &[&str]) {
+        self.nginx_rtmp_relay_info.remove_label_values(labels).ok();
+        self.nginx_rtmp_relay_incoming_bytes_total.remove_label_values(labels).ok();
+        self.nginx_rtmp_relay_outgoing_bytes_total.remove_label_values(labels).ok();
+    }
+
+    /// Remove every per-client series with the given label values, except for
+    /// client info.
+    pub fn remove_client(&self, labels: &[&str]) {
+        self.nginx_rtmp_client_connected_seconds.remove_label_values(labels).ok();
+        self.nginx_rtmp_client_dropped_frames_total.remove_label_values(labels).ok();
+    }
+}
@@ -0,0 +1,180 @@
+//! Handles classifying the clients of a stream by their role.
+use std::{fs, net::IpAddr, path::Path};
+
+use anyhow::{Context, Result};
+use ipnet::IpNet;
+use regex::Regex;
+use serde::{Deserialize, Deserializer};
+
+use crate::{
+    meta::Format,
+    xml::{ClientRole, RtmpStreamClient},
+};
+
+/// A rule assigning a role to the clients it matches. A client matches a rule
+/// if it matches every condition of the rule.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ClientRule {
+    /// The role of the clients matching this rule.
+    pub role: ClientRole,
+    /// The networks the address of the client must be in, if any.
+    #[serde(default)]
+    pub address: Vec<IpNet>,
+    /// A pattern the flash version of the client must match.
+    #[serde(default, deserialize_with = "deserialize_regex")]
+    pub flashver: Option<Regex>,
+    /// A pattern the page URL of the client must match.
+    #[serde(default, deserialize_with = "deserialize_regex")]
+    pub pageurl: Option<Regex>,
+}
+
+impl ClientRule {
+    /// This method checks if the given client matches this rule.
+    pub fn matches(&self, client: &RtmpStreamClient) -> bool {
+        let address = client.address.as_deref().and_then(|address| address.parse::<IpAddr>().ok());
+        (self.address.is_empty()
+            || address.is_some_and(|address| {
+                self.address.iter().any(|network| network.contains(&address))
+            }))
+            && matches_pattern(&self.flashver, client.flashver.as_deref())
+            && matches_pattern(&self.pageurl, client.pageurl.as_deref())
+    }
+}
+
+/// Check a value against an optional pattern. A missing value never matches a
+/// pattern.
+fn matches_pattern(pattern: &Option<Regex>, value: Option<&str>) -> bool {
+    match pattern {
+        Some(pattern) => value.is_some_and(|value| pattern.is_match(value)),
+        None => true,
+    }
+}
+
+fn deserialize_regex<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Regex>, D::Error> {
+    Option::<String>::deserialize(deserializer)?
+        .map(|pattern| Regex::new(&pattern).map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+/// An ordered list of rules classifying the clients of a stream. The first
+/// matching rule decides the role of a client, and clients matching no rule
+/// are classified by [`RtmpStreamClient::role`].
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct ClientRules {
+    #[serde(default)]
+    pub rules: Vec<ClientRule>,
+}
+
+impl ClientRules {
+    /// Load client rules from a file, specifying the file format.
+    pub fn from_path(path: impl AsRef<Path>, format: Format) -> Result<Self> {
+        let file = fs::read_to_string(path).context("Failed to read client rules file")?;
+        match format {
+            Format::Json => {
+                serde_json::from_str(&file).context("Failed to parse client rules file")
+            }
+            Format::Toml => toml::from_str(&file).context("Failed to parse client rules file"),
+            Format::Yaml => {
+                serde_yaml::from_str(&file).context("Failed to parse client rules file")
+            }
+        }
+    }
+
+    /// This method returns the role of the given client.
+    pub fn classify(&self, client: &RtmpStreamClient) -> ClientRole {
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(client))
+            .map(|rule| rule.role)
+            .unwrap_or_else(|| client.role())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ClientRules;
+    use crate::xml::{ClientRole, RtmpStreamClient};
+
+    fn client(address: &str, flashver: &str, publishing: bool) -> RtmpStreamClient {
+        let xml = format!(
+            "<client><id>1</id><address>{}</address><time>0</time><flashver>{}</flashver>\
+             <pageurl>https://player.example.com/watch</pageurl><dropped>0</dropped>\
+             <avsync>0</avsync><timestamp>0</timestamp>{}</client>",
+            address,
+            flashver,
+            if publishing { "<publishing/>" } else { "" }
+        );
+        quick_xml::de::from_str(&xml).unwrap()
+    }
+
+    const RULES: &str = r#"
+[[rules]]
+role = "local_relay"
+address = ["fd00::/8"]
+flashver = "^ngx-local-relay$"
+
+[[rules]]
+role = "remote_relay"
+flashver = "^edge-relay/"
+
+[[rules]]
+role = "viewer"
+address = ["203.0.113.0/24"]
+pageurl = "^https://player\\.example\\.com/"
+"#;
+
+    #[test]
+    fn test_classify_default() {
+        let rules = ClientRules::default();
+        assert_eq!(
+            rules.classify(&client("127.0.0.1", "ngx-local-relay", true)),
+            ClientRole::LocalRelay
+        );
+        assert_eq!(
+            rules.classify(&client("fd00::1", "ngx-local-relay", true)),
+            ClientRole::RemoteRelay
+        );
+        assert_eq!(
+            rules.classify(&client("198.51.100.1", "FMLE/3.0", true)),
+            ClientRole::Publisher
+        );
+        assert_eq!(
+            rules.classify(&client("198.51.100.1", "LNX 9,0,124,2", false)),
+            ClientRole::Viewer
+        );
+    }
+
+    #[test]
+    fn test_classify_rules() {
+        let rules: ClientRules = toml::from_str(RULES).unwrap();
+        // an IPv6 unique local address
+        assert_eq!(
+            rules.classify(&client("fd00::1", "ngx-local-relay", true)),
+            ClientRole::LocalRelay
+        );
+        // a custom relay flash version
+        assert_eq!(
+            rules.classify(&client("198.51.100.1", "edge-relay/1.0", true)),
+            ClientRole::RemoteRelay
+        );
+        // every condition of a rule must match
+        assert_eq!(rules.classify(&client("203.0.113.7", "FMLE/3.0", true)), ClientRole::Viewer);
+        assert_eq!(
+            rules.classify(&client("198.51.100.1", "FMLE/3.0", true)),
+            ClientRole::Publisher
+        );
+    }
+
+    #[test]
+    fn test_invalid_rules() {
+        assert!(toml::from_str::<ClientRules>("[[rules]]\nrole = \"viewer\"\nflashver = \"(\"")
+            .is_err());
+        assert!(toml::from_str::<ClientRules>(
+            "[[rules]]\nrole = \"viewer\"\naddress = [\"fd00::\"]"
+        )
+        .is_err());
+        assert!(toml::from_str::<ClientRules>("[[rules]]\nrole = \"relay\"").is_err());
+    }
+}
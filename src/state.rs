@@ -0,0 +1,585 @@
+//! Tracks the state of each target between scrapes, so that totals reported by
+//! NGINX can be exported as monotonic counters, and series which are no longer
+//! reported can be removed.
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    time::{Duration, Instant},
+};
+
+use crate::xml::{RtmpApplicationPlayBlock, RtmpStats, RtmpStream, RtmpStreamClient};
+
+/// The key of an application, made up of the index of its server block and its
+/// name.
+pub type ApplicationKey = (String, String);
+
+/// The key of a stream, made up of its server, application and stream name.
+pub type StreamKey = (String, String, String);
+
+/// The key of a client, made up of its server, application, stream and client
+/// id.
+pub type ClientKey = (String, String, String, u32);
+
+/// The number of bytes transferred since the previous scrape.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Delta {
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+}
+
+/// The change in the server-level stats of a target since the previous scrape.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ServerDelta {
+    /// Whether NGINX has restarted since the previous scrape.
+    pub restarted: bool,
+    /// The bytes transferred since the previous scrape.
+    pub bytes: Delta,
+    /// The number of connections accepted since the previous scrape.
+    pub accepted: u64,
+}
+
+/// Tracks the label values of a set of series across scrapes.
+#[derive(Debug)]
+pub struct SeriesSet<K> {
+    series: HashMap<K, (Vec<String>, Instant)>,
+}
+
+impl<K> Default for SeriesSet<K> {
+    fn default() -> Self {
+        Self { series: HashMap::new() }
+    }
+}
+
+impl<K: Hash + Eq + Clone> SeriesSet<K> {
+    /// Mark a series as seen at the given instant, returning its previous label
+    /// values if they have changed since it was last seen.
+    pub fn observe(&mut self, key: K, labels: Vec<String>, now: Instant) -> Option<Vec<String>> {
+        match self.series.insert(key, (labels.clone(), now)) {
+            Some((previous, _)) if previous != labels => Some(previous),
+            _ => None,
+        }
+    }
+
+    /// Forget a series, returning its label values if it was known.
+    pub fn remove(&mut self, key: &K) -> Option<Vec<String>> {
+        self.series.remove(key).map(|(labels, _)| labels)
+    }
+
+    /// Forget all series that have not been seen within the grace period,
+    /// returning their keys and label values.
+    pub fn expire(&mut self, now: Instant, grace: Duration) -> Vec<(K, Vec<String>)> {
+        let expired: Vec<K> = self
+            .series
+            .iter()
+            .filter(|(_, (_, seen))| now.duration_since(*seen) > grace)
+            .map(|(key, _)| key.clone())
+            .collect();
+        expired
+            .into_iter()
+            .filter_map(|key| self.series.remove(&key).map(|(labels, _)| (key, labels)))
+            .collect()
+    }
+}
+
+/// The state of a target as of its last successful scrape.
+#[derive(Debug, Default)]
+pub struct TargetState {
+    /// The server-level state, if the target has been scraped before.
+    server: Option<ServerState>,
+    /// The state of each stream.
+    streams: HashMap<StreamKey, StreamState>,
+    /// The label values of the build info series.
+    pub build_info: SeriesSet<()>,
+    /// The label values of each per-application series.
+    pub applications: SeriesSet<ApplicationKey>,
+    /// The bytes sent to each play client, keyed by application, then by stream
+    /// and client id.
+    play_clients: HashMap<ApplicationKey, HashMap<(String, u32), u64>>,
+    /// The label values of each application info series.
+    pub application_info: SeriesSet<ApplicationKey>,
+    /// The label values of each per-stream series.
+    pub stream_series: SeriesSet<StreamKey>,
+    /// The label values of each video info series.
+    pub video_info: SeriesSet<StreamKey>,
+    /// The label values of each audio info series.
+    pub audio_info: SeriesSet<StreamKey>,
+    /// The label values of each per-recorder series, keyed by stream and
+    /// recorder.
+    pub recorders: SeriesSet<(StreamKey, String)>,
+    /// The number of frames dropped by each client.
+    clients: HashMap<ClientKey, u64>,
+    /// The label values of each per-client series.
+    pub client_series: SeriesSet<ClientKey>,
+    /// The label values of each client info series.
+    pub client_info: SeriesSet<ClientKey>,
+    /// The bytes transferred by each relay.
+    relays: HashMap<ClientKey, Delta>,
+    /// The label values of each per-relay series.
+    pub relay_series: SeriesSet<ClientKey>,
+}
+
+#[derive(Debug)]
+struct ServerState {
+    pid: u32,
+    uptime: u32,
+    naccepted: u32,
+    bytes_in: u64,
+    bytes_out: u64,
+}
+
+#[derive(Debug, Default)]
+struct StreamState {
+    time: u64,
+    bytes_in: u64,
+    bytes_out: u64,
+}
+
+/// Build the key of a stream of the given application.
+pub fn stream_key(application: &ApplicationKey, stream: &str) -> StreamKey {
+    (application.0.clone(), application.1.clone(), stream.to_owned())
+}
+
+/// Build the key of a client of the given stream.
+pub fn client_key(application: &ApplicationKey, stream: &str, id: u32) -> ClientKey {
+    (application.0.clone(), application.1.clone(), stream.to_owned(), id)
+}
+
+/// Compute the increase of a total, treating a reset or a decrease as the
+/// total having restarted from zero.
+fn increase(previous: u64, current: u64, reset: bool) -> u64 {
+    if reset || current < previous {
+        current
+    } else {
+        current - previous
+    }
+}
+
+impl TargetState {
+    /// Observe the server-level stats of a target, returning the change since
+    /// the previous scrape.
+    ///
+    /// If NGINX has restarted since the previous scrape, detected by a change
+    /// of `pid` or a decrease in `uptime`, the totals reported by NGINX are
+    /// taken to have restarted from zero, including those of every stream.
+    pub fn observe_server(&mut self, stats: &RtmpStats) -> ServerDelta {
+        let previous = self.server.replace(ServerState {
+            pid: stats.pid,
+            uptime: stats.uptime,
+            naccepted: stats.naccepted,
+            bytes_in: stats.bytes_in,
+            bytes_out: stats.bytes_out,
+        });
+        match previous {
+            Some(previous) => {
+                let restarted = previous.pid != stats.pid || stats.uptime < previous.uptime;
+                if restarted {
+                    self.streams.values_mut().for_each(|stream| *stream = StreamState::default());
+                    self.clients.values_mut().for_each(|dropped| *dropped = 0);
+                    self.play_clients.clear();
+                    self.relays.values_mut().for_each(|relay| *relay = Delta::default());
+                }
+                ServerDelta {
+                    restarted,
+                    bytes: Delta {
+                        bytes_in: increase(previous.bytes_in, stats.bytes_in, restarted),
+                        bytes_out: increase(previous.bytes_out, stats.bytes_out, restarted),
+                    },
+                    accepted: increase(
+                        previous.naccepted as u64,
+                        stats.naccepted as u64,
+                        restarted,
+                    ),
+                }
+            }
+            None => ServerDelta {
+                restarted: false,
+                bytes: Delta { bytes_in: stats.bytes_in, bytes_out: stats.bytes_out },
+                accepted: stats.naccepted as u64,
+            },
+        }
+    }
+
+    /// Observe the stats of a stream, returning the bytes transferred since the
+    /// previous scrape.
+    ///
+    /// A stream that has been republished since the previous scrape is detected
+    /// by a decrease in its `time`, in which case its totals are taken to have
+    /// restarted from zero.
+    pub fn observe_stream(&mut self, application: &ApplicationKey, stream: &RtmpStream) -> Delta {
+        let previous = self.streams.insert(
+            stream_key(application, &stream.name),
+            StreamState {
+                time: stream.time,
+                bytes_in: stream.bytes_in,
+                bytes_out: stream.bytes_out,
+            },
+        );
+        match previous {
+            Some(previous) => {
+                let republished = stream.time < previous.time;
+                Delta {
+                    bytes_in: increase(previous.bytes_in, stream.bytes_in, republished),
+                    bytes_out: increase(previous.bytes_out, stream.bytes_out, republished),
+                }
+            }
+            None => Delta { bytes_in: stream.bytes_in, bytes_out: stream.bytes_out },
+        }
+    }
+
+    /// Observe a client, returning the number of frames it has dropped since the
+    /// previous scrape.
+    pub fn observe_client(
+        &mut self,
+        application: &ApplicationKey,
+        stream: &str,
+        client: &RtmpStreamClient,
+    ) -> u64 {
+        let key = client_key(application, stream, client.id);
+        match self.clients.insert(key, client.dropped) {
+            Some(previous) => increase(previous, client.dropped, false),
+            None => client.dropped,
+        }
+    }
+
+    /// Forget all clients that have not been seen within the grace period,
+    /// returning the label values of their series.
+    pub fn expire_clients(&mut self, now: Instant, grace: Duration) -> Vec<Vec<String>> {
+        self.client_series
+            .expire(now, grace)
+            .into_iter()
+            .map(|(key, labels)| {
+                self.clients.remove(&key);
+                labels
+            })
+            .collect()
+    }
+
+    /// Observe a relay, returning the bytes it has transferred since the
+    /// previous scrape.
+    pub fn observe_relay(
+        &mut self,
+        application: &ApplicationKey,
+        stream: &str,
+        client: &RtmpStreamClient,
+    ) -> Delta {
+        let key = client_key(application, stream, client.id);
+        let current = Delta { bytes_in: client.bytes_in, bytes_out: client.bytes_out };
+        match self.relays.insert(key, current) {
+            Some(previous) => Delta {
+                bytes_in: increase(previous.bytes_in, client.bytes_in, false),
+                bytes_out: increase(previous.bytes_out, client.bytes_out, false),
+            },
+            None => current,
+        }
+    }
+
+    /// Forget all relays that have not been seen within the grace period,
+    /// returning the label values of their series.
+    pub fn expire_relays(&mut self, now: Instant, grace: Duration) -> Vec<Vec<String>> {
+        self.relay_series
+            .expire(now, grace)
+            .into_iter()
+            .map(|(key, labels)| {
+                self.relays.remove(&key);
+                labels
+            })
+            .collect()
+    }
+
+    /// Observe the play section of an application, returning the bytes sent to
+    /// its clients since the previous scrape. Bytes sent to a client between the
+    /// previous scrape and it disconnecting are not counted.
+    pub fn observe_play(
+        &mut self,
+        application: &ApplicationKey,
+        play: &RtmpApplicationPlayBlock,
+    ) -> u64 {
+        let previous = self.play_clients.remove(application).unwrap_or_default();
+        let current: HashMap<(String, u32), u64> = play
+            .streams
+            .iter()
+            .flat_map(|stream| {
+                stream
+                    .clients
+                    .iter()
+                    .map(|client| ((stream.name.clone(), client.id), client.bytes_out))
+            })
+            .collect();
+        let sent = current
+            .iter()
+            .map(|(key, bytes_out)| match previous.get(key) {
+                Some(previous) => increase(*previous, *bytes_out, false),
+                None => *bytes_out,
+            })
+            .sum();
+        self.play_clients.insert(application.clone(), current);
+        sent
+    }
+
+    /// Forget all applications that have not been seen within the grace
+    /// period, returning the label values of their series.
+    pub fn expire_applications(&mut self, now: Instant, grace: Duration) -> Vec<Vec<String>> {
+        self.applications
+            .expire(now, grace)
+            .into_iter()
+            .map(|(key, labels)| {
+                self.play_clients.remove(&key);
+                labels
+            })
+            .collect()
+    }
+
+    /// Forget all streams that have not been seen within the grace period,
+    /// returning the label values of their series.
+    pub fn expire_streams(&mut self, now: Instant, grace: Duration) -> Vec<Vec<String>> {
+        self.stream_series
+            .expire(now, grace)
+            .into_iter()
+            .map(|(key, labels)| {
+                self.streams.remove(&key);
+                labels
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{stream_key, ApplicationKey, Delta, SeriesSet, ServerDelta, TargetState};
+    use crate::xml::{RtmpStats, RtmpStream};
+
+    fn app(name: &str) -> ApplicationKey {
+        ("0".to_owned(), name.to_owned())
+    }
+
+    fn stats(pid: u32, uptime: u32, bytes_in: u64, bytes_out: u64) -> RtmpStats {
+        stats_accepted(pid, uptime, bytes_in, bytes_out, 0)
+    }
+
+    fn stats_accepted(
+        pid: u32,
+        uptime: u32,
+        bytes_in: u64,
+        bytes_out: u64,
+        naccepted: u32,
+    ) -> RtmpStats {
+        let xml = format!(
+            r#"<rtmp>
+	<nginx_version>1.21.6</nginx_version>
+	<nginx_rtmp_version>1.2.x-dev</nginx_rtmp_version>
+	<compiler>gcc</compiler>
+	<pid>{}</pid>
+	<uptime>{}</uptime>
+	<naccepted>{}</naccepted>
+	<bw_in>0</bw_in>
+	<bytes_in>{}</bytes_in>
+	<bw_out>0</bw_out>
+	<bytes_out>{}</bytes_out>
+	<server><application><name>live</name><live/></application></server>
+</rtmp>"#,
+            pid, uptime, naccepted, bytes_in, bytes_out
+        );
+        quick_xml::de::from_str(&xml).unwrap()
+    }
+
+    fn stream(time: u64, bytes_in: u64, bytes_out: u64) -> RtmpStream {
+        let xml = format!(
+            r#"<stream>
+	<name>test</name>
+	<time>{}</time>
+	<bw_in>0</bw_in>
+	<bytes_in>{}</bytes_in>
+	<bw_out>0</bw_out>
+	<bytes_out>{}</bytes_out>
+	<bw_audio>0</bw_audio>
+	<bw_video>0</bw_video>
+	<client><id>0</id><time>0</time><dropped>0</dropped><avsync>0</avsync><timestamp>0</timestamp></client>
+</stream>"#,
+            time, bytes_in, bytes_out
+        );
+        quick_xml::de::from_str(&xml).unwrap()
+    }
+
+    #[test]
+    fn test_server_delta() {
+        let mut state = TargetState::default();
+        assert_eq!(
+            state.observe_server(&stats(1, 10, 100, 50)).bytes,
+            Delta { bytes_in: 100, bytes_out: 50 }
+        );
+        assert_eq!(
+            state.observe_server(&stats(1, 20, 150, 60)).bytes,
+            Delta { bytes_in: 50, bytes_out: 10 }
+        );
+    }
+
+    #[test]
+    fn test_server_restart() {
+        let mut state = TargetState::default();
+        state.observe_server(&stats(1, 10, 100, 50));
+        // pid changed
+        assert_eq!(
+            state.observe_server(&stats_accepted(2, 30, 200, 80, 3)),
+            ServerDelta {
+                restarted: true,
+                bytes: Delta { bytes_in: 200, bytes_out: 80 },
+                accepted: 3
+            }
+        );
+        // uptime decreased
+        assert_eq!(
+            state.observe_server(&stats_accepted(2, 5, 10, 5, 1)),
+            ServerDelta {
+                restarted: true,
+                bytes: Delta { bytes_in: 10, bytes_out: 5 },
+                accepted: 1
+            }
+        );
+        // still running
+        assert_eq!(
+            state.observe_server(&stats_accepted(2, 20, 30, 5, 4)),
+            ServerDelta {
+                restarted: false,
+                bytes: Delta { bytes_in: 20, bytes_out: 0 },
+                accepted: 3
+            }
+        );
+    }
+
+    #[test]
+    fn test_stream_delta() {
+        let mut state = TargetState::default();
+        assert_eq!(
+            state.observe_stream(&app("live"), &stream(1000, 10, 20)),
+            Delta { bytes_in: 10, bytes_out: 20 }
+        );
+        assert_eq!(
+            state.observe_stream(&app("live"), &stream(2000, 15, 30)),
+            Delta { bytes_in: 5, bytes_out: 10 }
+        );
+        // republished
+        assert_eq!(
+            state.observe_stream(&app("live"), &stream(500, 40, 40)),
+            Delta { bytes_in: 40, bytes_out: 40 }
+        );
+        // the same stream name in another application is tracked separately
+        assert_eq!(
+            state.observe_stream(&app("backup"), &stream(3000, 40, 40)),
+            Delta { bytes_in: 40, bytes_out: 40 }
+        );
+        // as is the same application in another server block
+        assert_eq!(
+            state.observe_stream(&("1".to_owned(), "live".to_owned()), &stream(3000, 40, 40)),
+            Delta { bytes_in: 40, bytes_out: 40 }
+        );
+    }
+
+    #[test]
+    fn test_restart_discards_streams() {
+        let mut state = TargetState::default();
+        state.observe_server(&stats(1, 10, 100, 50));
+        state.observe_stream(&app("live"), &stream(1000, 10, 20));
+        state.observe_server(&stats(2, 10, 100, 50));
+        assert_eq!(
+            state.observe_stream(&app("live"), &stream(5000, 60, 70)),
+            Delta { bytes_in: 60, bytes_out: 70 }
+        );
+    }
+
+    #[test]
+    fn test_series_set_labels_changed() {
+        let mut set = SeriesSet::default();
+        let now = Instant::now();
+        assert_eq!(set.observe("a", vec!["1".to_owned()], now), None);
+        assert_eq!(set.observe("a", vec!["1".to_owned()], now), None);
+        assert_eq!(set.observe("a", vec!["2".to_owned()], now), Some(vec!["1".to_owned()]));
+    }
+
+    #[test]
+    fn test_series_set_expire() {
+        let mut set = SeriesSet::default();
+        let start = Instant::now();
+        set.observe("a", vec!["a".to_owned()], start);
+        set.observe("b", vec!["b".to_owned()], start + Duration::from_secs(5));
+        // without a grace period, only series seen in the current scrape are kept
+        let now = start + Duration::from_secs(5);
+        assert_eq!(set.expire(now, Duration::ZERO), vec![("a", vec!["a".to_owned()])]);
+        // with a grace period, series are kept until it elapses
+        let now = start + Duration::from_secs(30);
+        assert!(set.expire(now, Duration::from_secs(60)).is_empty());
+        assert_eq!(set.expire(now, Duration::from_secs(10)), vec![("b", vec!["b".to_owned()])]);
+    }
+
+    #[test]
+    fn test_expire_streams_discards_counter_state() {
+        let mut state = TargetState::default();
+        let start = Instant::now();
+        state.observe_stream(&app("live"), &stream(1000, 10, 20));
+        state.stream_series.observe(stream_key(&app("live"), "test"), vec![], start);
+        let now = start + Duration::from_secs(1);
+        assert_eq!(state.expire_streams(now, Duration::ZERO), vec![Vec::<String>::new()]);
+        assert_eq!(
+            state.observe_stream(&app("live"), &stream(2000, 15, 30)),
+            Delta { bytes_in: 15, bytes_out: 30 }
+        );
+    }
+
+    #[test]
+    fn test_client_dropped_frames() {
+        let mut state = TargetState::default();
+        let mut client = stream(1000, 0, 0).clients.remove(0);
+        client.dropped = 5;
+        assert_eq!(state.observe_client(&app("live"), "test", &client), 5);
+        client.dropped = 8;
+        assert_eq!(state.observe_client(&app("live"), "test", &client), 3);
+        // client ids are reused after a restart
+        state.observe_server(&stats(1, 10, 0, 0));
+        state.observe_server(&stats(2, 10, 0, 0));
+        client.dropped = 2;
+        assert_eq!(state.observe_client(&app("live"), "test", &client), 2);
+    }
+
+    #[test]
+    fn test_relay_bytes() {
+        let mut state = TargetState::default();
+        let mut client = stream(1000, 0, 0).clients.remove(0);
+        client.bytes_in = 100;
+        client.bytes_out = 10;
+        assert_eq!(
+            state.observe_relay(&app("live"), "test", &client),
+            Delta { bytes_in: 100, bytes_out: 10 }
+        );
+        client.bytes_in = 250;
+        assert_eq!(
+            state.observe_relay(&app("live"), "test", &client),
+            Delta { bytes_in: 150, bytes_out: 0 }
+        );
+        // the relay reconnected with the same id
+        client.bytes_in = 50;
+        assert_eq!(
+            state.observe_relay(&app("live"), "test", &client),
+            Delta { bytes_in: 50, bytes_out: 0 }
+        );
+    }
+
+    #[test]
+    fn test_play_bytes_sent() {
+        let play = |clients: &str| {
+            let xml = format!("<play><stream><name>movie.mp4</name>{}</stream></play>", clients);
+            quick_xml::de::from_str(&xml).unwrap()
+        };
+        let mut state = TargetState::default();
+        let first = play("<client><id>1</id><bytes_out>100</bytes_out></client>");
+        assert_eq!(state.observe_play(&app("vod"), &first), 100);
+        let second = play(
+            "<client><id>1</id><bytes_out>150</bytes_out></client>\
+             <client><id>2</id><bytes_out>30</bytes_out></client>",
+        );
+        assert_eq!(state.observe_play(&app("vod"), &second), 80);
+        // the first client disconnected
+        let third = play("<client><id>2</id><bytes_out>40</bytes_out></client>");
+        assert_eq!(state.observe_play(&app("vod"), &third), 10);
+    }
+}
@@ -1,8 +1,12 @@
-use std::{error::Error, net::IpAddr};
+use std::{fmt, marker::PhantomData, net::IpAddr, ops::Deref};
 
-use serde::Deserialize;
-
-use crate::context::Context;
+use anyhow::Result;
+use quick_xml::escape::escape;
+use reqwest::{Client, Url};
+use serde::{
+    de::{DeserializeOwned, IgnoredAny, MapAccess, SeqAccess, Visitor},
+    Deserialize, Deserializer,
+};
 
 #[derive(Debug, Deserialize)]
 pub struct RtmpStats {
@@ -16,25 +20,94 @@ pub struct RtmpStats {
     pub bytes_in: u64,
     pub bw_out: u64,
     pub bytes_out: u64,
-    pub server: RtmpServerBlock,
+    /// The `server {}` blocks of the `rtmp {}` block, in the order they are
+    /// declared.
+    #[serde(rename = "server", default)]
+    pub servers: Vec<RtmpServerBlock>,
+}
+
+impl RtmpStats {
+    /// Return the errors of all elements that were skipped while parsing,
+    /// alongside the path of the skipped element.
+    pub fn parse_errors(&self) -> Vec<(&'static str, &str)> {
+        self.servers.iter().flat_map(RtmpServerBlock::parse_errors).collect()
+    }
 }
 
 #[derive(Debug, Deserialize)]
 pub struct RtmpServerBlock {
-    #[serde(rename = "application")]
-    pub applications: Vec<RtmpApplication>,
+    #[serde(rename = "application", default)]
+    pub applications: Lenient<RtmpApplication>,
+}
+
+impl RtmpServerBlock {
+    fn parse_errors(&self) -> impl Iterator<Item = (&'static str, &str)> {
+        let applications =
+            self.applications.errors.iter().map(|err| ("server.application", err.as_str()));
+        let streams = self.applications.iter().flat_map(|application| {
+            application.live.iter().flat_map(|live| {
+                live.streams
+                    .errors
+                    .iter()
+                    .map(|err| ("server.application.live.stream", err.as_str()))
+            })
+        });
+        let play_streams = self.applications.iter().flat_map(|application| {
+            application.play.iter().flat_map(|play| {
+                play.streams
+                    .errors
+                    .iter()
+                    .map(|err| ("server.application.play.stream", err.as_str()))
+            })
+        });
+        applications.chain(streams).chain(play_streams)
+    }
 }
 
 #[derive(Debug, Deserialize)]
 pub struct RtmpApplication {
     pub name: String,
-    pub live: RtmpApplicationLiveBlock,
+    pub live: Option<RtmpApplicationLiveBlock>,
+    pub play: Option<RtmpApplicationPlayBlock>,
+    pub record: Option<IgnoredAny>,
+}
+
+impl RtmpApplication {
+    /// The live streams of this application, if it has a live section.
+    pub fn live_streams(&self) -> &[RtmpStream] {
+        self.live.as_ref().map(|live| &*live.streams).unwrap_or_default()
+    }
+}
+
+/// The play section of an application, listing the streams being played from
+/// disk or HTTP.
+#[derive(Debug, Deserialize)]
+pub struct RtmpApplicationPlayBlock {
+    #[serde(rename = "stream", default)]
+    pub streams: Lenient<RtmpPlayStream>,
+    #[serde(default)]
+    pub nclients: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RtmpPlayStream {
+    pub name: String,
+    #[serde(rename = "client", default)]
+    pub clients: Vec<RtmpPlayClient>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RtmpPlayClient {
+    pub id: u32,
+    /// The bytes sent to this client, if reported by this version of NGINX.
+    #[serde(default)]
+    pub bytes_out: u64,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct RtmpApplicationLiveBlock {
-    #[serde(rename = "stream", default = "Vec::new")]
-    pub streams: Vec<RtmpStream>,
+    #[serde(rename = "stream", default)]
+    pub streams: Lenient<RtmpStream>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -47,9 +120,37 @@ pub struct RtmpStream {
     pub bw_out: u64,
     pub bw_audio: u64,
     pub bw_video: u64,
-    #[serde(rename = "client")]
+    #[serde(rename = "client", default)]
     pub clients: Vec<RtmpStreamClient>,
     pub meta: Option<RtmpStreamMeta>,
+    pub record: Option<RtmpStreamRecordBlock>,
+}
+
+impl RtmpStream {
+    /// The recorders of this stream, if its application records streams.
+    pub fn recorders(&self) -> &[RtmpStreamRecorder] {
+        self.record.as_ref().map(|record| &*record.recorders).unwrap_or_default()
+    }
+}
+
+/// The record section of a stream, listing the state of each recorder of its
+/// application.
+#[derive(Debug, Deserialize)]
+pub struct RtmpStreamRecordBlock {
+    #[serde(rename = "recorder", default)]
+    pub recorders: Vec<RtmpStreamRecorder>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RtmpStreamRecorder {
+    /// The name of the recorder, which is empty for the default recorder.
+    #[serde(default)]
+    pub id: String,
+    /// Present if this recorder is currently writing the stream to disk.
+    pub recording: Option<()>,
+    /// The bytes written to the current recording.
+    #[serde(default)]
+    pub bytes: u64,
 }
 
 #[derive(Debug, Deserialize)]
@@ -59,18 +160,103 @@ pub struct RtmpStreamClient {
     pub time: u64,
     pub flashver: Option<String>,
     pub pageurl: Option<String>,
+    #[serde(default)]
+    pub bytes_in: u64,
+    #[serde(default)]
+    pub bytes_out: u64,
     pub dropped: u64,
     pub avsync: i64,
+    /// The current timestamp of the stream of this client, in milliseconds.
+    /// Not exported, as it only tracks the position within the stream.
+    #[allow(dead_code)]
     pub timestamp: u64,
     pub publishing: Option<()>,
+    /// Present once this client is sending or receiving media. Not exported,
+    /// as the client counts include every connected client.
+    #[allow(dead_code)]
     pub active: Option<()>,
 }
 
+/// The role of a client connected to a stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClientRole {
+    /// The client is publishing the stream.
+    Publisher,
+    /// The client is playing the stream.
+    Viewer,
+    /// The client is a relay to or from a server on the same network.
+    LocalRelay,
+    /// The client is a relay to or from a remote server.
+    RemoteRelay,
+}
+
+impl ClientRole {
+    /// The value of the `role` label for this role.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ClientRole::Publisher => "publisher",
+            ClientRole::Viewer => "viewer",
+            ClientRole::LocalRelay => "local_relay",
+            ClientRole::RemoteRelay => "remote_relay",
+        }
+    }
+
+    /// This method checks if this role is either kind of relay.
+    pub fn is_relay(&self) -> bool {
+        matches!(self, ClientRole::LocalRelay | ClientRole::RemoteRelay)
+    }
+}
+
+/// The direction of a relay, relative to this server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelayDirection {
+    /// The relay pushes the stream from this server to its peer.
+    Push,
+    /// The relay pulls the stream from its peer into this server.
+    Pull,
+}
+
+impl RelayDirection {
+    /// The value of the `direction` label for this direction.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RelayDirection::Push => "push",
+            RelayDirection::Pull => "pull",
+        }
+    }
+}
+
 impl RtmpStreamClient {
+    /// This method returns the role of this client, as decided by its flash
+    /// version and address.
+    pub fn role(&self) -> ClientRole {
+        if self.is_local_relay() {
+            ClientRole::LocalRelay
+        } else if self.is_relay() {
+            ClientRole::RemoteRelay
+        } else if self.publishing.is_some() {
+            ClientRole::Publisher
+        } else {
+            ClientRole::Viewer
+        }
+    }
+
     /// This method checks if this client is a relay.
     pub fn is_relay(&self) -> bool {
         self.flashver == Some("ngx-local-relay".to_owned())
     }
+    /// This method returns the direction of this client, assuming it is a
+    /// relay. A pull relay publishes the stream it pulls, while a push relay
+    /// plays the stream it pushes.
+    pub fn relay_direction(&self) -> RelayDirection {
+        if self.publishing.is_some() {
+            RelayDirection::Pull
+        } else {
+            RelayDirection::Push
+        }
+    }
+
     /// This method checks if this client is a local relay.
     pub fn is_local_relay(&self) -> bool {
         // check if this client is a local relay
@@ -95,53 +281,195 @@ impl RtmpStreamClient {
 #[derive(Debug, Deserialize)]
 pub struct RtmpStreamMeta {
     pub video: RtmpStreamVideoMeta,
-    pub audio: RtmpStreamAudioMetaWrapper,
+    #[serde(default)]
+    pub audio: RtmpStreamAudioMeta,
 }
 
+/// The video metadata of a stream. NGINX omits the codec of streams without
+/// video, and the profile, compatibility and level of codecs other than AVC.
 #[derive(Debug, Deserialize)]
 pub struct RtmpStreamVideoMeta {
     pub width: u16,
     pub height: u64,
     pub frame_rate: f32,
-    pub codec: String,
-    pub profile: String,
-    pub compat: u16,
-    pub level: f32,
+    #[serde(default)]
+    pub codec: Option<String>,
+    #[serde(default)]
+    pub profile: Option<String>,
+    /// Not exported, as the AVC compatibility flags are rarely of interest.
+    #[serde(default)]
+    #[allow(dead_code)]
+    pub compat: Option<u16>,
+    #[serde(default)]
+    pub level: Option<f32>,
 }
 
-#[derive(Debug, Deserialize)]
-pub struct RtmpStreamAudioMetaWrapper {
-    pub inner: Option<RtmpStreamAudioMeta>,
+/// The audio metadata of a stream. NGINX emits an empty `<audio/>` element for
+/// streams without audio, and omits any fields it does not know.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct RtmpStreamAudioMeta {
+    pub codec: Option<String>,
+    pub profile: Option<String>,
+    pub channels: Option<u8>,
+    pub sample_rate: Option<u32>,
+    pub data_rate: Option<u32>,
 }
 
-#[derive(Debug, Deserialize)]
-pub struct RtmpStreamAudioMeta {
-    pub codec: String,
-    pub profile: String,
-    pub channels: u8,
-    pub sample_rate: u32,
+/// A generic XML element, captured so that it can be parsed in isolation.
+enum XmlNode {
+    Text(String),
+    Element(Vec<(String, XmlNode)>),
 }
 
-impl Context {
-    /// This method fetches the RTMP stats from the given URL.
-    #[tracing::instrument(skip_all)]
-    pub async fn fetch_rtmp_stats(&self) -> Result<RtmpStats, Box<dyn Error>> {
-        let req = self.http.get(self.rtmp_stats_endpoint.clone()).build()?;
-        let text = self.http.execute(req).await?.text().await?;
-        let mut de = quick_xml::de::Deserializer::from_str(&text);
-        serde_path_to_error::deserialize(&mut de).map_err(|err| err.into())
+impl XmlNode {
+    /// Write this node back out as XML, as an element with the given name.
+    fn write(&self, name: &str, out: &mut String) {
+        match self {
+            XmlNode::Text(text) if name == "$text" => out.push_str(&escape(text)),
+            XmlNode::Text(text) => {
+                out.push_str(&format!("<{}>{}</{}>", name, escape(text), name));
+            }
+            XmlNode::Element(children) => {
+                out.push_str(&format!("<{}>", name));
+                children.iter().for_each(|(name, child)| child.write(name, out));
+                out.push_str(&format!("</{}>", name));
+            }
+        }
+    }
+
+    /// Find the text of the child element with the given name.
+    fn child_text(&self, name: &str) -> Option<&str> {
+        let XmlNode::Element(children) = self else {
+            return None;
+        };
+        match children.iter().find(|(child, _)| child == name).map(|(_, node)| node)? {
+            XmlNode::Text(text) => Some(text),
+            node => node.child_text("$text"),
+        }
     }
 }
 
+impl<'de> Deserialize<'de> for XmlNode {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct XmlNodeVisitor;
+
+        impl<'de> Visitor<'de> for XmlNodeVisitor {
+            type Value = XmlNode;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("an XML element")
+            }
+
+            fn visit_str<E>(self, text: &str) -> Result<XmlNode, E> {
+                Ok(XmlNode::Text(text.to_owned()))
+            }
+
+            fn visit_string<E>(self, text: String) -> Result<XmlNode, E> {
+                Ok(XmlNode::Text(text))
+            }
+
+            fn visit_unit<E>(self) -> Result<XmlNode, E> {
+                Ok(XmlNode::Element(Vec::new()))
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<XmlNode, A::Error> {
+                let mut children = Vec::new();
+                while let Some(child) = map.next_entry()? {
+                    children.push(child);
+                }
+                Ok(XmlNode::Element(children))
+            }
+        }
+
+        deserializer.deserialize_any(XmlNodeVisitor)
+    }
+}
+
+/// A list of repeated elements, each of which is parsed in isolation. Elements
+/// which fail to parse are skipped, and their errors kept, rather than failing
+/// the whole document.
+#[derive(Debug)]
+pub struct Lenient<T> {
+    items: Vec<T>,
+    /// The errors of the elements that were skipped.
+    pub errors: Vec<String>,
+}
+
+impl<T> Default for Lenient<T> {
+    fn default() -> Self {
+        Self { items: Vec::new(), errors: Vec::new() }
+    }
+}
+
+impl<T> Deref for Lenient<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        &self.items
+    }
+}
+
+impl<'de, T: DeserializeOwned> Deserialize<'de> for Lenient<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct LenientVisitor<T>(PhantomData<T>);
+
+        impl<'de, T: DeserializeOwned> Visitor<'de> for LenientVisitor<T> {
+            type Value = Lenient<T>;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a sequence of XML elements")
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Lenient<T>, A::Error> {
+                let mut lenient = Lenient::default();
+                while let Some(node) = seq.next_element::<XmlNode>()? {
+                    // write the element back out, and parse it on its own
+                    let mut xml = String::new();
+                    node.write("element", &mut xml);
+                    match quick_xml::de::from_str(&xml) {
+                        Ok(item) => lenient.items.push(item),
+                        Err(err) => lenient.errors.push(match node.child_text("name") {
+                            Some(name) => format!("{}: {}", name, err),
+                            None => err.to_string(),
+                        }),
+                    }
+                }
+                Ok(lenient)
+            }
+        }
+
+        deserializer.deserialize_seq(LenientVisitor(PhantomData))
+    }
+}
+
+/// This method fetches the RTMP stats from the given URL.
+#[tracing::instrument(skip_all, fields(url = %url))]
+pub async fn fetch_rtmp_stats(http: &Client, url: &Url) -> Result<RtmpStats> {
+    let req = http.get(url.clone()).build()?;
+    let text = http.execute(req).await?.error_for_status()?.text().await?;
+    let mut de = quick_xml::de::Deserializer::from_str(&text);
+    serde_path_to_error::deserialize(&mut de).map_err(|err| err.into())
+}
+
 #[cfg(test)]
 mod tests {
-    use super::{RtmpStats, RtmpStreamAudioMetaWrapper};
+    use super::{
+        ClientRole, RelayDirection, RtmpApplication, RtmpStats, RtmpStream, RtmpStreamAudioMeta,
+    };
+
+    const STATS: &str = include_str!("../test/stat_xml.xml");
 
     #[test]
     fn test_deserialize_nginx_stats() {
-        let xml = include_str!("../test/stat_xml.xml");
-        let mut de = quick_xml::de::Deserializer::from_str(xml);
-        let _stats: RtmpStats = serde_path_to_error::deserialize(&mut de).unwrap();
+        let mut de = quick_xml::de::Deserializer::from_str(STATS);
+        let stats: RtmpStats = serde_path_to_error::deserialize(&mut de).unwrap();
+        let stream = &stats.servers[0].applications[0].live_streams()[0];
+        let meta = stream.meta.as_ref().expect("stream has no meta");
+        assert_eq!(meta.video.width, 1920);
+        assert_eq!(meta.video.codec.as_deref(), Some("H264"));
+        assert_eq!(meta.video.level, Some(4.2));
+        assert!(meta.audio.codec.is_none());
     }
 
     #[test]
@@ -154,6 +482,199 @@ mod tests {
 	<data_rate>312</data_rate>
 </audio>"#;
 
-        let _: RtmpStreamAudioMetaWrapper = quick_xml::de::from_str(audio).unwrap();
+        let audio: RtmpStreamAudioMeta = quick_xml::de::from_str(audio).unwrap();
+        assert_eq!(audio.codec.as_deref(), Some("AAC"));
+        assert_eq!(audio.profile.as_deref(), Some("LC"));
+        assert_eq!(audio.channels, Some(2));
+        assert_eq!(audio.sample_rate, Some(48000));
+        assert_eq!(audio.data_rate, Some(312));
+    }
+
+    #[test]
+    fn test_deserialize_empty_audio() {
+        let audio: RtmpStreamAudioMeta = quick_xml::de::from_str("<audio/>").unwrap();
+        assert!(audio.codec.is_none());
+        assert!(audio.profile.is_none());
+        assert!(audio.channels.is_none());
+        assert!(audio.sample_rate.is_none());
+        assert!(audio.data_rate.is_none());
+    }
+
+    #[test]
+    fn test_deserialize_audio_only_stream() {
+        let stats = STATS
+            .lines()
+            .filter(|line| {
+                !["<codec>", "<profile>", "<compat>", "<level>"]
+                    .iter()
+                    .any(|tag| line.contains(tag))
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        let stats: RtmpStats = quick_xml::de::from_str(&stats).unwrap();
+        let streams = stats.servers[0].applications[0].live_streams();
+        assert_eq!(streams.len(), 1);
+        let video = &streams[0].meta.as_ref().expect("stream has no meta").video;
+        assert!(video.codec.is_none());
+        assert!(video.profile.is_none());
+        assert!(video.compat.is_none());
+        assert!(video.level.is_none());
+    }
+
+    #[test]
+    fn test_deserialize_application_sections() {
+        let application = r#"<application>
+	<name>vod</name>
+	<play>
+		<nclients>0</nclients>
+	</play>
+</application>"#;
+
+        let application: RtmpApplication = quick_xml::de::from_str(application).unwrap();
+        assert!(application.live.is_none());
+        assert!(application.play.is_some());
+        assert!(application.record.is_none());
+        assert!(application.live_streams().is_empty());
+    }
+
+    #[test]
+    fn test_deserialize_skips_malformed_stream() {
+        // duplicate the stream, breaking the first copy
+        let start = STATS.find("<stream>").unwrap();
+        let end = STATS.find("</stream>").unwrap() + "</stream>".len();
+        let stream = &STATS[start..end];
+        let broken = stream.replace("my cool stream", "broken").replace("<time>1234", "<time>-1");
+        let xml = STATS.replacen(stream, &format!("{}{}", broken, stream), 1);
+
+        let stats: RtmpStats = quick_xml::de::from_str(&xml).unwrap();
+        let streams = stats.servers[0].applications[0].live_streams();
+        assert_eq!(streams.len(), 1);
+        assert_eq!(streams[0].name, "my cool stream");
+        // the stream was fully parsed, including its clients and metadata
+        assert_eq!(streams[0].clients.len(), 1);
+        assert!(streams[0].meta.is_some());
+
+        let errors = stats.parse_errors();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].0, "server.application.live.stream");
+        assert!(errors[0].1.starts_with("broken: "));
+    }
+
+    #[test]
+    fn test_deserialize_skips_malformed_application() {
+        let xml = STATS.replacen("<live>", "<name>duplicate</name><live>", 1);
+
+        let stats: RtmpStats = quick_xml::de::from_str(&xml).unwrap();
+        assert!(stats.servers[0].applications.is_empty());
+        assert_eq!(stats.parse_errors()[0].0, "server.application");
+    }
+
+    #[test]
+    fn test_deserialize_play() {
+        let application = r#"<application>
+	<name>vod</name>
+	<play>
+		<stream>
+			<name>movie.mp4</name>
+			<client>
+				<id>3</id>
+				<address>203.0.113.7</address>
+				<time>5000</time>
+				<flashver>LNX 9,0,124,2</flashver>
+				<bytes_out>4096</bytes_out>
+			</client>
+			<client>
+				<id>4</id>
+				<address>203.0.113.8</address>
+				<time>1000</time>
+			</client>
+			<active/>
+			<nclients>2</nclients>
+		</stream>
+		<nclients>2</nclients>
+	</play>
+</application>"#;
+
+        let application: RtmpApplication = quick_xml::de::from_str(application).unwrap();
+        let play = application.play.expect("application has no play section");
+        assert_eq!(play.nclients, 2);
+        assert_eq!(play.streams.len(), 1);
+        assert_eq!(play.streams[0].name, "movie.mp4");
+        assert_eq!(play.streams[0].clients.len(), 2);
+        assert_eq!(play.streams[0].clients[0].bytes_out, 4096);
+        assert_eq!(play.streams[0].clients[1].bytes_out, 0);
+    }
+
+    #[test]
+    fn test_deserialize_recorders() {
+        let start = STATS.find("<stream>").unwrap();
+        let end = STATS.find("</stream>").unwrap() + "</stream>".len();
+        let stream: RtmpStream = quick_xml::de::from_str(&STATS[start..end]).unwrap();
+        assert!(stream.recorders().is_empty());
+
+        let record = r#"<record>
+	<recorder>
+		<id></id>
+		<recording/>
+		<bytes>1048576</bytes>
+	</recorder>
+	<recorder>
+		<id>archive</id>
+	</recorder>
+</record></stream>"#;
+        let xml = STATS[start..end].replace("</stream>", record);
+        let stream: RtmpStream = quick_xml::de::from_str(&xml).unwrap();
+        let recorders = stream.recorders();
+        assert_eq!(recorders.len(), 2);
+        assert_eq!(recorders[0].id, "");
+        assert!(recorders[0].recording.is_some());
+        assert_eq!(recorders[0].bytes, 1048576);
+        assert_eq!(recorders[1].id, "archive");
+        assert!(recorders[1].recording.is_none());
+        assert_eq!(recorders[1].bytes, 0);
+    }
+
+    #[test]
+    fn test_deserialize_multiple_servers() {
+        let start = STATS.find("<server>").unwrap();
+        let end = STATS.find("</server>").unwrap() + "</server>".len();
+        let server = &STATS[start..end];
+        let xml = STATS.replacen(server, &format!("{}{}", server, server), 1);
+
+        let stats: RtmpStats = quick_xml::de::from_str(&xml).unwrap();
+        assert_eq!(stats.servers.len(), 2);
+        assert_eq!(stats.servers[0].applications[0].name, stats.servers[1].applications[0].name);
+    }
+
+    #[test]
+    fn test_client_role() {
+        let start = STATS.find("<stream>").unwrap();
+        let end = STATS.find("</stream>").unwrap() + "</stream>".len();
+        let xml = &STATS[start..end];
+        // the only client is a relay pulling the stream from a remote server
+        let stream: RtmpStream = quick_xml::de::from_str(xml).unwrap();
+        assert_eq!(stream.clients[0].role(), ClientRole::RemoteRelay);
+        assert_eq!(stream.clients[0].relay_direction(), RelayDirection::Pull);
+        assert_eq!(stream.clients[0].bytes_in, 123456);
+
+        // a relay from the same network
+        let stream: RtmpStream =
+            quick_xml::de::from_str(&xml.replace("example", "10.0.0.2")).unwrap();
+        assert_eq!(stream.clients[0].role(), ClientRole::LocalRelay);
+
+        // a publisher, and a viewer
+        let viewer = "<client><id>1</id><time>0</time><dropped>0</dropped><avsync>0</avsync>\
+                      <timestamp>0</timestamp></client><meta>";
+        let xml = xml.replace("ngx-local-relay", "FMLE/3.0").replacen("<meta>", viewer, 1);
+        let stream: RtmpStream = quick_xml::de::from_str(&xml).unwrap();
+        assert_eq!(stream.clients[0].role(), ClientRole::Publisher);
+        assert_eq!(stream.clients[1].role(), ClientRole::Viewer);
+
+        // a stream without clients
+        let start = xml.find("<client>").unwrap();
+        let end = xml.rfind("</client>").unwrap() + "</client>".len();
+        let stream: RtmpStream =
+            quick_xml::de::from_str(&format!("{}{}", &xml[..start], &xml[end..])).unwrap();
+        assert!(stream.clients.is_empty());
     }
 }